@@ -7,6 +7,7 @@ mod sid_bench;
 criterion_group!(
     benches,
     sid_bench::bench_sid,
+    sid_bench::bench_sample,
     sampler_bench::bench_compute_convolution_fir
 );
 
@@ -1,5 +1,5 @@
 use criterion::Criterion;
-use resid::{ChipModel, Sid};
+use resid::{ChipModel, SamplingMethod, Sid};
 
 pub fn bench_sid(c: &mut Criterion) {
     c.bench_function("clock_delta", |b| {
@@ -13,3 +13,24 @@ pub fn bench_sid(c: &mut Criterion) {
         b.iter(|| sid.clock_delta(22))
     });
 }
+
+pub fn bench_sample(c: &mut Criterion) {
+    let methods = [
+        ("sample_resample", SamplingMethod::Resample),
+        ("sample_resample_two_pass", SamplingMethod::ResampleTwoPass),
+    ];
+    for &(name, method) in methods.iter() {
+        c.bench_function(name, move |b| {
+            let mut sid = Sid::new(ChipModel::Mos6581);
+            sid.set_sampling_parameters(method, 985_248, 44100).unwrap();
+            sid.write(0x05, 0x09); // AD1
+            sid.write(0x06, 0x00); // SR1
+            sid.write(0x18, 0x0f); // MODVOL
+            sid.write(0x01, 25); // FREQHI1
+            sid.write(0x00, 177); // FREQLO1
+            sid.write(0x04, 0x21); // CR1
+            let mut buffer = [0i16; 64];
+            b.iter(|| sid.sample(1000, &mut buffer, 1))
+        });
+    }
+}
@@ -3,6 +3,7 @@
 // Portions (c) 2004 Dag Lem <resid@nimrod.no>
 // Licensed under the GPLv3. See LICENSE file in the project root for full license text.
 
+mod dac;
 mod spline6581_f0;
 mod spline8580_f0;
 mod wave6581_ps;
@@ -14,6 +15,7 @@ mod wave8580_pst;
 mod wave8580_pt;
 mod wave8580_st;
 
+pub use self::dac::{DAC6581_ENV, DAC6581_WAVE, DAC8580_ENV, DAC8580_WAVE};
 pub use self::spline6581_f0::SPLINE6581_F0;
 pub use self::spline8580_f0::SPLINE8580_F0;
 pub use self::wave6581_ps::WAVE6581_PS;
@@ -54,7 +54,7 @@ const RATE_COUNTER_MSB_MASK: u16 = 0x8000;
 // The described method is thus sufficient for exact calculation of the rate
 // periods.
 //
-static RATE_COUNTER_PERIOD: [u16; 16] = [
+pub(crate) static RATE_COUNTER_PERIOD: [u16; 16] = [
     9,     // 2ms*1.0MHz/256 = 7.81
     32,    // 8ms*1.0MHz/256 = 31.25
     63,    // 16ms*1.0MHz/256 = 62.50
@@ -80,6 +80,11 @@ static SUSTAIN_LEVEL: [u8; 16] = [
     0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
 ];
 
+/// Checks whether a rate counter period is one of the envelope rates.
+pub(crate) fn is_rate_counter_period(period: u16) -> bool {
+    RATE_COUNTER_PERIOD.contains(&period)
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum State {
     Attack,
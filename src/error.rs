@@ -0,0 +1,87 @@
+// This file is part of resid-rs.
+// Copyright (c) 2017-2019 Sebastian Jastrzebski <sebby2k@gmail.com>. All rights reserved.
+// Portions (c) 2004 Dag Lem <resid@nimrod.no>
+// Licensed under the GPLv3. See LICENSE file in the project root for full license text.
+
+use core::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Buffer cannot hold the encoded snapshot, resampling filter table or
+    /// resampling history.
+    BufferTooSmall,
+    /// Input ends before the encoded data is complete.
+    Truncated,
+    /// Input does not start with the snapshot signature.
+    InvalidMagic,
+    /// Snapshot was written by an incompatible format version.
+    UnsupportedVersion(u16),
+    /// Snapshot checksum does not match its contents.
+    ChecksumMismatch,
+    /// Section length does not match its contents.
+    InvalidSection(u16),
+    /// State field holds a value the emulator cannot reach.
+    InvalidState(&'static str),
+    /// State holds resampling history or pending writes, which are only
+    /// kept with the `alloc` feature.
+    StateIncomplete,
+    /// Clock or sample frequency is zero.
+    InvalidFrequency,
+    /// Clock to sample frequency ratio is out of the supported range.
+    UnsupportedSamplingRatio,
+    /// Resampler passband exceeds 90% of the output bandwidth.
+    PassbandTooWide,
+    /// Resampler passband limit is zero.
+    PassbandEmpty,
+    /// Resampler filter scale is outside of [0.9, 1.0].
+    InvalidFilterScale,
+    /// Resampler stopband attenuation is outside of [21, 120] dB.
+    InvalidAttenuation,
+    /// Resampler latency limit is below half the shortest filter.
+    LatencyTooLow,
+    /// SIMD kernel is not supported by the CPU.
+    UnsupportedSimd,
+    /// Sample rate ratio is outside of [0.9, 1.1].
+    InvalidRateRatio,
+    /// Chip index is out of range.
+    InvalidChip,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::BufferTooSmall => write!(f, "buffer too small"),
+            Error::Truncated => write!(f, "unexpected end of data"),
+            Error::InvalidMagic => write!(f, "invalid snapshot signature"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            Error::ChecksumMismatch => write!(f, "snapshot checksum mismatch"),
+            Error::InvalidSection(tag) => write!(f, "invalid snapshot section {}", tag),
+            Error::InvalidState(field) => write!(f, "invalid state field {}", field),
+            Error::StateIncomplete => write!(f, "state history requires alloc"),
+            Error::InvalidFrequency => write!(f, "invalid sampling frequency"),
+            Error::UnsupportedSamplingRatio => write!(f, "unsupported sampling ratio"),
+            Error::PassbandTooWide => write!(f, "resampler passband too wide"),
+            Error::PassbandEmpty => write!(f, "resampler passband empty"),
+            Error::InvalidFilterScale => write!(f, "invalid resampler filter scale"),
+            Error::InvalidAttenuation => write!(f, "invalid resampler attenuation"),
+            Error::LatencyTooLow => write!(f, "resampler latency limit too low"),
+            Error::UnsupportedSimd => write!(f, "unsupported simd kernel"),
+            Error::InvalidRateRatio => write!(f, "invalid sample rate ratio"),
+            Error::InvalidChip => write!(f, "invalid chip index"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl alloc::error::Error for Error {}
+
+/// Checks a state field, naming it in the error.
+pub(crate) fn check(valid: bool, field: &'static str) -> Result<(), Error> {
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidState(field))
+    }
+}
@@ -36,9 +36,9 @@ pub struct ExternalFilter {
     w0_lp: i32,
     w0_hp: i32,
     // Runtime State
-    vlp: i32,
-    vhp: i32,
-    vo: i32,
+    pub vlp: i32,
+    pub vhp: i32,
+    pub vo: i32,
 }
 
 impl ExternalFilter {
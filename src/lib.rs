@@ -12,10 +12,14 @@ extern crate std as alloc;
 
 mod data;
 pub mod envelope;
+mod error;
 pub mod external_filter;
 pub mod filter;
+pub mod multi_sid;
+mod queue;
 pub mod sampler;
 mod sid;
+mod snapshot;
 pub mod spline;
 pub mod synth;
 pub mod voice;
@@ -30,5 +34,9 @@ pub enum ChipModel {
     Mos8580,
 }
 
-pub use self::sampler::SamplingMethod;
-pub use self::sid::Sid;
+pub use self::error::Error;
+pub use self::multi_sid::{MultiSid, MultiState};
+#[cfg(feature = "alloc")]
+pub use self::sampler::{FirCache, FirTable};
+pub use self::sampler::{OutputStats, ResampleParams, SamplingMethod, Simd};
+pub use self::sid::{ChipState, Sid, State};
@@ -207,3 +207,29 @@ pub fn sqrt(x: f64) -> f64 {
     ix0 += m << 20;
     f64::from_bits((ix0 as u64) << 32 | ix1 as u64)
 }
+
+/// Floating point methods missing from `core`.
+pub trait F64Ext {
+    fn ln(self) -> f64;
+    fn log10(self) -> f64;
+    fn powf(self, n: f64) -> f64;
+    fn sin(self) -> f64;
+}
+
+impl F64Ext for f64 {
+    fn ln(self) -> f64 {
+        libm::log(self)
+    }
+
+    fn log10(self) -> f64 {
+        libm::log10(self)
+    }
+
+    fn powf(self, n: f64) -> f64 {
+        libm::pow(self, n)
+    }
+
+    fn sin(self) -> f64 {
+        libm::sin(self)
+    }
+}
@@ -0,0 +1,565 @@
+// This file is part of resid-rs.
+// Copyright (c) 2017-2019 Sebastian Jastrzebski <sebby2k@gmail.com>. All rights reserved.
+// Portions (c) 2004 Dag Lem <resid@nimrod.no>
+// Licensed under the GPLv3. See LICENSE file in the project root for full license text.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use super::error::Error;
+use super::queue::{Scheduled, WriteQueue};
+#[cfg(feature = "alloc")]
+use super::sampler::FirTable;
+use super::sampler::{
+    saturate, History, OutputStats, ResampleParams, Sample, Sampler, SamplingMethod, Simd, Source,
+    RING_SIZE, TWO_PASS_RING_SIZE,
+};
+use super::sid::{age_bus_value, ChipState, BUS_VALUE_TTL};
+use super::synth::Synth;
+use super::ChipModel;
+
+// Mixing levels are kept as 4.12 fixpoint.
+const LEVEL_SHIFT: i32 = 12;
+
+/// Stereo mix of several SID chips. Each chip is scaled by its gain and
+/// panned using a balance law, i.e. a centered chip plays at full level
+/// on both channels and moving it to one side attenuates the other one.
+#[derive(Clone, Copy)]
+pub struct Mixer<const N: usize> {
+    pub synths: [Synth; N],
+    gain: [f32; N],
+    pan: [f32; N],
+    master_gain: f32,
+    level_left: [i32; N],
+    level_right: [i32; N],
+}
+
+impl<const N: usize> Mixer<N> {
+    fn new(chip_models: [ChipModel; N]) -> Self {
+        let mut mixer = Mixer {
+            synths: chip_models.map(Synth::new),
+            gain: [1.0; N],
+            pan: [0.0; N],
+            master_gain: 1.0,
+            level_left: [0; N],
+            level_right: [0; N],
+        };
+        for i in 0..N {
+            mixer.set_levels(i);
+        }
+        mixer
+    }
+
+    fn set_levels(&mut self, chip: usize) {
+        let left = if self.pan[chip] > 0.0 {
+            1.0 - self.pan[chip]
+        } else {
+            1.0
+        };
+        let right = if self.pan[chip] < 0.0 {
+            1.0 + self.pan[chip]
+        } else {
+            1.0
+        };
+        let scale = self.gain[chip] * self.master_gain * (1 << LEVEL_SHIFT) as f32;
+        self.level_left[chip] = (left * scale + 0.5) as i32;
+        self.level_right[chip] = (right * scale + 0.5) as i32;
+    }
+}
+
+impl<const N: usize> Source<2> for Mixer<N> {
+    #[inline]
+    fn clock(&mut self) {
+        for synth in self.synths.iter_mut() {
+            synth.clock();
+        }
+    }
+
+    #[inline]
+    fn clock_delta(&mut self, delta: u32) {
+        for synth in self.synths.iter_mut() {
+            synth.clock_delta(delta);
+        }
+    }
+
+    #[inline]
+    fn output_frame(&self) -> [i32; 2] {
+        let mut left = 0;
+        let mut right = 0;
+        for i in 0..N {
+            let output = self.synths[i].output_raw() as i64;
+            left += ((output * self.level_left[i] as i64) >> LEVEL_SHIFT) as i32;
+            right += ((output * self.level_right[i] as i64) >> LEVEL_SHIFT) as i32;
+        }
+        [left, right]
+    }
+
+    fn reset(&mut self) {
+        for synth in self.synths.iter_mut() {
+            synth.reset();
+        }
+    }
+}
+
+/// Snapshot of the runtime state of a `MultiSid`: the state of each chip
+/// and of the shared sampler, see `State`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultiState<const N: usize> {
+    /// Chip states, each with its bus value and pending writes.
+    pub chips: [ChipState; N],
+    pub cycles_elapsed: u64,
+    // Sampler
+    pub sampler_offset: i32,
+    pub sampler_prev_sample: [i32; 2],
+    pub sampler_ring_shift: i32,
+    pub sampler_delay: u32,
+    #[cfg(feature = "alloc")]
+    pub sampler_ring: Vec<i16>,
+    #[cfg(feature = "alloc")]
+    pub sampler_two_pass_offset: u32,
+    #[cfg(feature = "alloc")]
+    pub sampler_two_pass_remaining: u32,
+    #[cfg(feature = "alloc")]
+    pub sampler_two_pass_ring: Vec<i16>,
+}
+
+impl<const N: usize> MultiState<N> {
+    /// Checks the chip states and the sampler fields, see `State::validate`.
+    pub fn validate(&self) -> Result<(), Error> {
+        for chip in self.chips.iter() {
+            chip.validate()?;
+        }
+        self.history().validate()
+    }
+
+    fn history(&self) -> History<'_, 2> {
+        History {
+            offset: self.sampler_offset,
+            prev_sample: self.sampler_prev_sample,
+            ring_shift: self.sampler_ring_shift,
+            delay: self.sampler_delay,
+            #[cfg(feature = "alloc")]
+            ring: &self.sampler_ring,
+            #[cfg(feature = "alloc")]
+            two_pass: (
+                self.sampler_two_pass_offset,
+                self.sampler_two_pass_remaining,
+                &self.sampler_two_pass_ring,
+            ),
+            // Without a heap the state holds no history, restore silence.
+            #[cfg(not(feature = "alloc"))]
+            ring: &[],
+            #[cfg(not(feature = "alloc"))]
+            two_pass: (0, 0, &[]),
+        }
+    }
+}
+
+/// Several SID chips sharing a clock, e.g. 2SID/3SID setups with chips
+/// mapped at $D400/$D420/$D500. The chips are mixed into a stereo signal
+/// which is then sampled once, so the resampling cost does not grow with
+/// the number of chips. Chips are addressed by their index. The sampler
+/// keeps a resampling history of up to `R` stereo samples, `F` filter
+/// table entries inline and a two-pass resampling history of up to `P`
+/// stereo samples, see `Sid::with_capacity`.
+#[derive(Clone)]
+pub struct MultiSid<
+    const N: usize,
+    const R: usize = RING_SIZE,
+    const F: usize = 0,
+    const P: usize = TWO_PASS_RING_SIZE,
+> {
+    // Functional Units
+    sampler: Sampler<Mixer<N>, 2, R, F, P>,
+    // Runtime State
+    bus_value: [u8; N],
+    bus_value_ttl: [u32; N],
+    cycles_elapsed: u64,
+    queue: [WriteQueue; N],
+}
+
+impl<const N: usize> MultiSid<N> {
+    pub fn new(chip_models: [ChipModel; N]) -> Self {
+        MultiSid::with_capacity(chip_models)
+    }
+}
+
+impl<const N: usize, const R: usize, const F: usize, const P: usize> MultiSid<N, R, F, P> {
+    /// See `Sid::with_capacity`.
+    pub fn with_capacity(chip_models: [ChipModel; N]) -> Self {
+        let mut sid = MultiSid {
+            sampler: Sampler::with_capacity(Mixer::new(chip_models)),
+            bus_value: [0; N],
+            bus_value_ttl: [0; N],
+            cycles_elapsed: 0,
+            queue: core::array::from_fn(|_| WriteQueue::default()),
+        };
+        sid.set_sampling_parameters(SamplingMethod::Fast, 985_248, 44100)
+            .expect("default sampling parameters");
+        sid
+    }
+
+    /// See `Sid::set_sampling_parameters`.
+    pub fn set_sampling_parameters(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+    ) -> Result<(), Error> {
+        self.sampler.set_parameters(method, clock_freq, sample_freq)
+    }
+
+    /// See `Sid::set_sampling_parameters_with`.
+    pub fn set_sampling_parameters_with(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<(), Error> {
+        self.sampler
+            .set_parameters_with(method, clock_freq, sample_freq, params)
+    }
+
+    /// See `Sid::set_sampling_parameters_inline`.
+    pub fn set_sampling_parameters_inline(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<(), Error> {
+        self.sampler
+            .set_parameters_inline(method, clock_freq, sample_freq, params)
+    }
+
+    /// See `Sid::change_sampling_parameters`.
+    pub fn change_sampling_parameters(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<(), Error> {
+        self.sampler
+            .change_parameters(method, clock_freq, sample_freq, params)
+    }
+
+    /// See `Sid::set_sampling_parameters_shared`.
+    #[cfg(feature = "alloc")]
+    pub fn set_sampling_parameters_shared(&mut self, table: &FirTable) -> Result<(), Error> {
+        self.sampler.set_parameters_shared(table)
+    }
+
+    /// See `Sid::set_rate_ratio`.
+    pub fn set_rate_ratio(&mut self, ratio: f64) -> Result<(), Error> {
+        self.sampler.set_rate_ratio(ratio)
+    }
+
+    /// See `Sid::set_simd`.
+    pub fn set_simd(&mut self, simd: Simd) -> Result<(), Error> {
+        self.sampler.set_simd(simd)
+    }
+
+    /// Sets the gain of a chip, 1.0 being the level of a single SID.
+    pub fn set_gain(&mut self, chip: usize, gain: f32) -> Result<(), Error> {
+        self.check_chip(chip)?;
+        self.sampler.synth.gain[chip] = gain;
+        self.sampler.synth.set_levels(chip);
+        Ok(())
+    }
+
+    /// Sets the gain applied to the mix of all chips, 1.0 being unity gain.
+    /// Values below 0 are treated as 0. See `output_stats` for detecting
+    /// clipping.
+    pub fn set_master_gain(&mut self, gain: f32) {
+        self.sampler.synth.master_gain = gain.max(0.0);
+        for chip in 0..N {
+            self.sampler.synth.set_levels(chip);
+        }
+    }
+
+    /// Sets the stereo position of a chip from -1.0 (left) to 1.0 (right).
+    pub fn set_pan(&mut self, chip: usize, pan: f32) -> Result<(), Error> {
+        self.check_chip(chip)?;
+        self.sampler.synth.pan[chip] = pan.clamp(-1.0, 1.0);
+        self.sampler.synth.set_levels(chip);
+        Ok(())
+    }
+
+    fn check_chip(&self, chip: usize) -> Result<(), Error> {
+        if chip < N {
+            Ok(())
+        } else {
+            Err(Error::InvalidChip)
+        }
+    }
+
+    pub fn clock(&mut self) {
+        // Apply writes due on this cycle.
+        self.write_pending();
+        self.age_bus_values(1);
+        self.sampler.synth.clock();
+        self.advance(1);
+    }
+
+    pub fn clock_delta(&mut self, delta: u32) {
+        self.clock_scheduled(delta);
+    }
+
+    pub fn enable_external_filter(&mut self, enabled: bool) {
+        for synth in self.sampler.synth.synths.iter_mut() {
+            synth.ext_filter.set_enabled(enabled);
+        }
+    }
+
+    pub fn enable_filter(&mut self, enabled: bool) {
+        for synth in self.sampler.synth.synths.iter_mut() {
+            synth.filter.set_enabled(enabled);
+        }
+    }
+
+    /// See `Sid::enable_pipeline_delays`.
+    pub fn enable_pipeline_delays(&mut self, enabled: bool) {
+        for synth in self.sampler.synth.synths.iter_mut() {
+            for voice in synth.voices.iter_mut() {
+                voice.wave.set_pipeline_delays(enabled);
+            }
+        }
+    }
+
+    /// See `Sid::enable_dac_model`.
+    pub fn enable_dac_model(&mut self, enabled: bool) {
+        for synth in self.sampler.synth.synths.iter_mut() {
+            for voice in synth.voices.iter_mut() {
+                voice.set_dac_model(enabled);
+            }
+        }
+    }
+
+    /// See `Sid::enable_shift_register_reset`.
+    pub fn enable_shift_register_reset(&mut self, enabled: bool) {
+        for synth in self.sampler.synth.synths.iter_mut() {
+            for voice in synth.voices.iter_mut() {
+                voice.wave.set_shift_reset_delay(enabled);
+            }
+        }
+    }
+
+    /// See `Sid::enable_floating_output`.
+    pub fn enable_floating_output(&mut self, enabled: bool) {
+        for synth in self.sampler.synth.synths.iter_mut() {
+            for voice in synth.voices.iter_mut() {
+                voice.wave.set_floating_output(enabled);
+            }
+        }
+    }
+
+    /// See `Sid::set_floating_output_ttl`.
+    pub fn set_floating_output_ttl(&mut self, cycles: u32) {
+        for synth in self.sampler.synth.synths.iter_mut() {
+            for voice in synth.voices.iter_mut() {
+                voice.wave.set_floating_output_ttl(cycles);
+            }
+        }
+    }
+
+    /// See `Sid::set_floating_output_fade`.
+    pub fn set_floating_output_fade(&mut self, cycles: u32) {
+        for synth in self.sampler.synth.synths.iter_mut() {
+            for voice in synth.voices.iter_mut() {
+                voice.wave.set_floating_output_fade(cycles);
+            }
+        }
+    }
+
+    /// Mixed stereo output as a (left, right) pair.
+    pub fn output(&self) -> (i16, i16) {
+        let [left, right] = self.sampler.synth.output_frame();
+        (saturate(left), saturate(right))
+    }
+
+    pub fn reset(&mut self) {
+        self.sampler.reset();
+        self.bus_value = [0; N];
+        self.bus_value_ttl = [0; N];
+        for queue in self.queue.iter_mut() {
+            queue.reset();
+        }
+    }
+
+    /// Clocks all chips while producing interleaved stereo frames, see
+    /// `Sid::sample`. Returns the number of frames written and the number
+    /// of cycles left to clock.
+    pub fn sample(&mut self, delta: u32, buffer: &mut [i16]) -> (usize, u32) {
+        self.sample_buffer(delta, buffer)
+    }
+
+    /// Clocks all chips while producing interleaved floating point stereo
+    /// frames, see `Sid::sample_f32`.
+    pub fn sample_f32(&mut self, delta: u32, buffer: &mut [f32]) -> (usize, u32) {
+        self.sample_buffer(delta, buffer)
+    }
+
+    fn sample_buffer<T: Sample>(&mut self, delta: u32, buffer: &mut [T]) -> (usize, u32) {
+        self.sampler.reset_stats();
+        self.sample_scheduled(delta, buffer, 2)
+    }
+
+    /// Clipped sample count and peak level of the last `sample` call,
+    /// counting both channels.
+    pub fn output_stats(&self) -> OutputStats {
+        self.sampler.stats()
+    }
+
+    /// See `Sid::cycles_elapsed`.
+    pub fn cycles_elapsed(&self) -> u64 {
+        self.cycles_elapsed
+    }
+
+    /// See `Sid::output_latency_cycles`.
+    pub fn output_latency_cycles(&self) -> u32 {
+        self.sampler.latency()
+    }
+
+    // -- Device I/O
+
+    /// Reads a register of a chip. Fails with `Error::InvalidChip` if the
+    /// chip does not exist.
+    pub fn read(&self, chip: usize, reg: u8) -> Result<u8, Error> {
+        self.check_chip(chip)?;
+        Ok(self.sampler.synth.synths[chip].read(reg, self.bus_value[chip]))
+    }
+
+    /// Writes a register of a chip. Fails with `Error::InvalidChip` if the
+    /// chip does not exist.
+    pub fn write(&mut self, chip: usize, reg: u8, value: u8) -> Result<(), Error> {
+        self.check_chip(chip)?;
+        self.write_chip(chip, reg, value);
+        Ok(())
+    }
+
+    fn write_chip(&mut self, chip: usize, reg: u8, value: u8) {
+        self.bus_value[chip] = value;
+        self.bus_value_ttl[chip] = BUS_VALUE_TTL;
+        self.sampler.synth.synths[chip].write(reg, value);
+    }
+
+    /// Schedules a register write of a chip `cycle` cycles from the current
+    /// clock, see `Sid::write_at`. Returns false if the write queue of the
+    /// chip is full, the chip does not exist or `reg` is above 0x1f.
+    pub fn write_at(&mut self, chip: usize, cycle: u32, reg: u8, value: u8) -> bool {
+        match self.queue.get_mut(chip) {
+            Some(queue) => queue.push(cycle, reg, value),
+            None => false,
+        }
+    }
+
+    fn age_bus_values(&mut self, delta: u32) {
+        for chip in 0..N {
+            age_bus_value(
+                &mut self.bus_value[chip],
+                &mut self.bus_value_ttl[chip],
+                delta,
+            );
+        }
+    }
+
+    // -- State
+
+    /// Returns the state of all chips and the shared sampler, see
+    /// `Sid::read_state`.
+    pub fn read_state(&self) -> MultiState<N> {
+        let chips = core::array::from_fn(|chip| {
+            ChipState::read(
+                &self.sampler.synth.synths[chip],
+                self.bus_value[chip],
+                self.bus_value_ttl[chip],
+                &self.queue[chip],
+            )
+        });
+        #[cfg(feature = "alloc")]
+        let (two_pass_offset, two_pass_remaining, two_pass_ring) = self.sampler.read_two_pass();
+        MultiState {
+            chips,
+            cycles_elapsed: self.cycles_elapsed,
+            sampler_offset: self.sampler.offset,
+            sampler_prev_sample: self.sampler.prev_sample,
+            sampler_ring_shift: self.sampler.ring_shift,
+            sampler_delay: self.sampler.delay as u32,
+            #[cfg(feature = "alloc")]
+            sampler_ring: self.sampler.read_ring(),
+            #[cfg(feature = "alloc")]
+            sampler_two_pass_offset: two_pass_offset,
+            #[cfg(feature = "alloc")]
+            sampler_two_pass_remaining: two_pass_remaining,
+            #[cfg(feature = "alloc")]
+            sampler_two_pass_ring: two_pass_ring,
+        }
+    }
+
+    /// See `Sid::try_read_state`.
+    pub fn try_read_state(&self) -> Result<MultiState<N>, Error> {
+        let pending = self.queue.iter().any(|queue| !queue.is_empty());
+        if !cfg!(feature = "alloc") && (self.sampler.has_history() || pending) {
+            return Err(Error::StateIncomplete);
+        }
+        Ok(self.read_state())
+    }
+
+    /// Restores a state returned by `read_state`, see `Sid::write_state`.
+    pub fn write_state(&mut self, state: &MultiState<N>) -> Result<(), Error> {
+        state.validate()?;
+        let history = state.history();
+        self.sampler.check_history(&history)?;
+        for (chip, chip_state) in state.chips.iter().enumerate() {
+            chip_state.write(
+                &mut self.sampler.synth.synths[chip],
+                &mut self.bus_value[chip],
+                &mut self.bus_value_ttl[chip],
+                &mut self.queue[chip],
+            );
+        }
+        self.cycles_elapsed = state.cycles_elapsed;
+        self.sampler.write_history(&history);
+        Ok(())
+    }
+}
+
+impl<const N: usize, const R: usize, const F: usize, const P: usize> Scheduled
+    for MultiSid<N, R, F, P>
+{
+    fn write_pending(&mut self) {
+        for chip in 0..N {
+            while let Some((reg, value)) = self.queue[chip].pop() {
+                self.write_chip(chip, reg, value);
+            }
+        }
+    }
+
+    /// Number of cycles until the next write of any chip is due.
+    fn next_write_delta(&self) -> Option<u32> {
+        self.queue.iter().filter_map(WriteQueue::next_delta).min()
+    }
+
+    fn clock_direct(&mut self, delta: u32) {
+        self.age_bus_values(delta);
+        self.sampler.synth.clock_delta(delta);
+    }
+
+    fn clock_sampled<T: Sample>(
+        &mut self,
+        delta: u32,
+        buffer: &mut [T],
+        interleave: usize,
+    ) -> (usize, u32) {
+        self.sampler.clock(delta, buffer, interleave)
+    }
+
+    fn advance(&mut self, delta: u32) {
+        for queue in self.queue.iter_mut() {
+            queue.advance(delta);
+        }
+        self.cycles_elapsed += delta as u64;
+    }
+}
@@ -0,0 +1,191 @@
+// This file is part of resid-rs.
+// Copyright (c) 2017-2019 Sebastian Jastrzebski <sebby2k@gmail.com>. All rights reserved.
+// Portions (c) 2004 Dag Lem <resid@nimrod.no>
+// Licensed under the GPLv3. See LICENSE file in the project root for full license text.
+
+use core::cmp;
+
+use super::sampler::Sample;
+
+pub const QUEUE_SIZE: usize = 1024;
+/// Highest register address, the SID decodes 5 address bits.
+pub const REG_MAX: u8 = 0x1f;
+
+#[derive(Clone, Copy)]
+struct Write {
+    cycle: u32,
+    reg: u8,
+    value: u8,
+}
+
+/// Register writes scheduled at a cycle relative to the current SID clock.
+///
+/// Writes are kept in cycle order in a fixed size ring, so the queue works
+/// without an allocator. The cycle of each write is stored on the queue time
+/// base, a free running 32-bit cycle counter which is advanced as the SID is
+/// clocked. Every pending write is due within 2^32 cycles of the current
+/// time, so cycles are compared by their wrapping distance from it and the
+/// time base can wrap while writes are queued.
+#[derive(Clone)]
+pub struct WriteQueue {
+    writes: [Write; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    time: u32,
+}
+
+impl Default for WriteQueue {
+    fn default() -> Self {
+        WriteQueue {
+            writes: [Write {
+                cycle: 0,
+                reg: 0,
+                value: 0,
+            }; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+            time: 0,
+        }
+    }
+}
+
+impl WriteQueue {
+    /// Number of cycles until the next write is due.
+    #[inline]
+    pub fn next_delta(&self) -> Option<u32> {
+        if self.len != 0 {
+            Some(self.writes[self.head].cycle.wrapping_sub(self.time))
+        } else {
+            None
+        }
+    }
+
+    /// Advances the time base. Must not step past the next pending write.
+    #[inline]
+    pub fn advance(&mut self, delta: u32) {
+        self.time = self.time.wrapping_add(delta);
+    }
+
+    /// Removes the next write if it is due on the current cycle.
+    #[inline]
+    pub fn pop(&mut self) -> Option<(u8, u8)> {
+        if self.next_delta() == Some(0) {
+            let write = self.writes[self.head];
+            self.head = (self.head + 1) % QUEUE_SIZE;
+            self.len -= 1;
+            Some((write.reg, write.value))
+        } else {
+            None
+        }
+    }
+
+    /// Schedules a write `delta` cycles from now. Writes scheduled before
+    /// the last queued write are delayed to keep the queue in order.
+    /// Returns false if the queue is full or `reg` is not a SID register.
+    pub fn push(&mut self, delta: u32, reg: u8, value: u8) -> bool {
+        if self.len == QUEUE_SIZE || reg > REG_MAX {
+            return false;
+        }
+        let mut cycle = self.time.wrapping_add(delta);
+        if self.len != 0 {
+            let last = self.writes[(self.head + self.len - 1) % QUEUE_SIZE].cycle;
+            if delta < last.wrapping_sub(self.time) {
+                cycle = last;
+            }
+        }
+        self.writes[(self.head + self.len) % QUEUE_SIZE] = Write { cycle, reg, value };
+        self.len += 1;
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Pending writes in cycle order, as `(delta, reg, value)` with the
+    /// delta counted from now.
+    #[cfg(feature = "alloc")]
+    pub fn pending(&self) -> impl Iterator<Item = (u32, u8, u8)> + '_ {
+        (0..self.len).map(move |i| {
+            let write = &self.writes[(self.head + i) % QUEUE_SIZE];
+            (write.cycle.wrapping_sub(self.time), write.reg, write.value)
+        })
+    }
+
+    pub fn reset(&mut self) {
+        self.head = 0;
+        self.len = 0;
+        self.time = 0;
+    }
+}
+
+/// Clocking of chips with writes from write queues, shared by `Sid` and
+/// `MultiSid`. Clocking is split at each pending write, so the write is
+/// applied right before its cycle is clocked.
+pub(crate) trait Scheduled {
+    /// Applies the writes due on the current cycle.
+    fn write_pending(&mut self);
+
+    /// Number of cycles until the next write is due.
+    fn next_write_delta(&self) -> Option<u32>;
+
+    /// Clocks the chips without applying writes or advancing the queues.
+    fn clock_direct(&mut self, delta: u32);
+
+    /// Clocks the chips while sampling like `Sampler::clock`, without
+    /// applying writes or advancing the queues.
+    fn clock_sampled<T: Sample>(
+        &mut self,
+        delta: u32,
+        buffer: &mut [T],
+        interleave: usize,
+    ) -> (usize, u32);
+
+    /// Advances the write queues and the cycle count by clocked cycles.
+    fn advance(&mut self, delta: u32);
+
+    fn clock_scheduled(&mut self, mut delta: u32) {
+        // Clock up to each pending write.
+        while let Some(delta_write) = self.next_write_delta() {
+            if delta_write >= delta {
+                break;
+            }
+            if delta_write > 0 {
+                self.clock_direct(delta_write);
+                self.advance(delta_write);
+                delta -= delta_write;
+            }
+            self.write_pending();
+        }
+        if delta > 0 {
+            self.clock_direct(delta);
+            self.advance(delta);
+        }
+    }
+
+    fn sample_scheduled<T: Sample>(
+        &mut self,
+        mut delta: u32,
+        buffer: &mut [T],
+        interleave: usize,
+    ) -> (usize, u32) {
+        let mut index = 0;
+        loop {
+            self.write_pending();
+            let delta_step = match self.next_write_delta() {
+                Some(delta_write) if delta_write < delta => delta_write,
+                _ => delta,
+            };
+            let start = cmp::min(index * interleave, buffer.len());
+            let (samples, delta_rem) =
+                self.clock_sampled(delta_step, &mut buffer[start..], interleave);
+            index += samples;
+            self.advance(delta_step - delta_rem);
+            delta -= delta_step - delta_rem;
+            if delta_rem > 0 || delta == 0 {
+                break;
+            }
+        }
+        (index, delta)
+    }
+}
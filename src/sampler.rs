@@ -8,12 +8,15 @@
 
 use core::f64;
 
+#[cfg(feature = "alloc")]
+use alloc::sync::Arc;
 #[cfg(feature = "alloc")]
 use alloc::vec::Vec;
 
 #[cfg(not(feature = "std"))]
-use libm::F64Ext;
+use super::math::F64Ext;
 
+use super::error::{check, Error};
 #[cfg(not(feature = "std"))]
 use super::math;
 use super::synth::Synth;
@@ -28,132 +31,1178 @@ use super::synth::Synth;
 const FIR_RES_FAST: i32 = 51473;
 const FIR_RES_INTERPOLATE: i32 = 285;
 const FIR_SHIFT: i32 = 15;
-const RING_SIZE: usize = 16384;
+// Shortest filter order in output samples, i.e. the sinc main lobe.
+const FIR_ORDER_MIN: f64 = 2.0;
+const ATTENUATION_MIN: f64 = 21.0;
+const ATTENUATION_MAX: f64 = 120.0;
+/// Largest sample history kept by the resampling ring and the default ring
+/// capacity of `Sampler`.
+pub const RING_SIZE: usize = 16384;
+/// Default capacity of the intermediate sample ring of
+/// `SamplingMethod::ResampleTwoPass`, which fits the second step filter for
+/// sample frequencies down to about 1 kHz.
+pub const TWO_PASS_RING_SIZE: usize = 1024;
 
 const FIXP_SHIFT: i32 = 16;
 const FIXP_MASK: i32 = 0xffff;
+const MAX_CYCLES_PER_SAMPLE: f64 = (1 << 14) as f64;
+
+const OUTPUT_HALF: i32 = 1 << 15;
+const OUTPUT_SCALE: f32 = OUTPUT_HALF as f32;
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum SamplingMethod {
     Fast,
     Interpolate,
-    #[cfg(feature = "alloc")]
     Resample,
-    #[cfg(feature = "alloc")]
     ResampleFast,
+    /// Resampling in two steps through an intermediate frequency, see
+    /// `Sampler::clock_resample_two_pass`. Much cheaper than `Resample` at
+    /// the same passband, but both steps let aliases fold into their
+    /// transition band, so content above half the sampling frequency is
+    /// attenuated far less: at 44.1 kHz a tone 1 kHz above it comes out at
+    /// about -23 dB instead of -58 dB, and tones further up leak into the
+    /// whole output band at about -30 dB. Suited to sources with little
+    /// content above the passband.
+    ResampleTwoPass,
+}
+
+/// Resampling filter configuration used by `SamplingMethod::Resample`,
+/// `SamplingMethod::ResampleFast` and `SamplingMethod::ResampleTwoPass`.
+/// A narrower passband or lower stopband attenuation yields a shorter filter
+/// which is cheaper to compute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResampleParams {
+    /// Upper edge of the passband in Hz. Negative values select 20 kHz,
+    /// limited to 90% of the output bandwidth for low sample frequencies,
+    /// zero is rejected.
+    pub pass_freq: f64,
+    /// Filter gain, only included to avoid clipping. Valid range is [0.9, 1.0].
+    pub filter_scale: f64,
+    /// Stopband attenuation in dB. Valid range is [21, 120], well beyond
+    /// the 96 dB resolved by the 16-bit filter tables.
+    pub attenuation: f64,
+    /// Upper bound of the filter latency in clock cycles. The filters are
+    /// linear phase and delay the output by half their length, e.g. about
+    /// 1385 cycles (1.4 ms) for `Resample` at 985248 Hz to 44.1 kHz, and
+    /// about 740 cycles for `ResampleTwoPass`. Longer filters are shortened
+    /// to fit, trading stopband attenuation for latency, i.e. more aliasing
+    /// around the passband edge. The shortest filter delays the output by
+    /// about one output sample per step, lower limits are rejected. None
+    /// keeps the length required by the attenuation.
+    pub max_latency: Option<u32>,
+}
+
+impl Default for ResampleParams {
+    fn default() -> Self {
+        ResampleParams {
+            pass_freq: -1.0,
+            filter_scale: 0.97,
+            // 16 bits -> -96dB stopband attenuation.
+            attenuation: -20.0f64 * (1.0 / (1i32 << 16) as f64).log10(),
+            max_latency: None,
+        }
+    }
+}
+
+/// Filter table storage, shared on the heap or stored inline by the
+/// sampler.
+#[derive(Clone)]
+enum FirData {
+    #[cfg(feature = "alloc")]
+    Shared(Arc<[i16]>),
+    /// Offset of the table into the inline storage of the sampler.
+    Inline(usize),
 }
 
 #[derive(Clone)]
 struct Fir {
-    data: Vec<i16>,
+    data: FirData,
     n: i32,
     res: i32,
 }
 
+/// Filters and sampling rate of a sampling method.
+#[derive(Clone)]
+struct Filters {
+    /// Clock cycles per sample of the source, 16.16 fixpoint.
+    cycles_per_sample: u32,
+    fir: Fir,
+    /// Second step filter and intermediate samples per output sample,
+    /// 32.32 fixpoint, of two-pass resampling.
+    two_pass: Option<(Fir, u64)>,
+}
+
+impl Filters {
+    /// Designs and computes the filters, storing the tables in `storage` or
+    /// on the heap. The filters are checked to fit a ring of `capacity`
+    /// samples, and the second step of two-pass resampling a ring of
+    /// `two_pass_capacity` samples, before any table is computed.
+    fn new(
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+        capacity: usize,
+        two_pass_capacity: usize,
+        mut storage: Option<&mut [i16]>,
+    ) -> Result<Self, Error> {
+        let cycles_per_sample = check_sampling_ratio(clock_freq, sample_freq)?;
+        let cycles_per_sample = (cycles_per_sample * (1 << FIXP_SHIFT) as f64 + 0.5) as u32;
+        let (clock_freq, sample_freq) = (clock_freq as f64, sample_freq as f64);
+        match method {
+            SamplingMethod::Resample | SamplingMethod::ResampleFast => {
+                let design = FirDesign::resample(method, clock_freq, sample_freq, params)?;
+                check_ring_capacity(&design, capacity)?;
+                check_fir_storage(storage.as_deref(), design.len())?;
+                Ok(Filters {
+                    cycles_per_sample,
+                    fir: build_fir(&design, storage, 0)?,
+                    two_pass: None,
+                })
+            }
+            SamplingMethod::ResampleTwoPass => {
+                let design = TwoPassDesign::new(clock_freq, sample_freq, params)?;
+                check_ring_capacity(&design.fir1, capacity)?;
+                check_ring_capacity(&design.fir2, two_pass_capacity)?;
+                check_fir_storage(storage.as_deref(), design.len())?;
+                let fir1 = build_fir(&design.fir1, storage.as_deref_mut(), 0)?;
+                let fir2 = build_fir(&design.fir2, storage, design.fir1.len())?;
+                Ok(Filters {
+                    // The source is sampled at the intermediate frequency.
+                    cycles_per_sample: design.cycles_per_sample,
+                    fir: fir1,
+                    two_pass: Some((fir2, design.samples_per_output)),
+                })
+            }
+            _ => Ok(Filters {
+                cycles_per_sample,
+                fir: Fir {
+                    data: FirData::Inline(0),
+                    n: 0,
+                    res: 0,
+                },
+                two_pass: None,
+            }),
+        }
+    }
+}
+
+/// Precomputed filter tables of a sampling configuration. Computing the
+/// resampling filters is expensive, a table is thus computed once and shared
+/// by all samplers it is applied to, see `Sampler::set_parameters_shared`.
+/// Cloning a table is cheap.
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+pub struct FirTable {
+    method: SamplingMethod,
+    clock_freq: u32,
+    sample_freq: u32,
+    params: ResampleParams,
+    filters: Filters,
+}
+
+#[cfg(feature = "alloc")]
+impl FirTable {
+    /// Computes the filter tables of a sampling configuration. The
+    /// parameters are checked like by `Sampler::set_parameters_with`.
+    pub fn new(
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<Self, Error> {
+        let filters = Filters::new(
+            method,
+            clock_freq,
+            sample_freq,
+            params,
+            RING_SIZE,
+            RING_SIZE,
+            None,
+        )?;
+        Ok(FirTable {
+            method,
+            clock_freq,
+            sample_freq,
+            params,
+            filters,
+        })
+    }
+
+    pub fn method(&self) -> SamplingMethod {
+        self.method
+    }
+
+    pub fn clock_freq(&self) -> u32 {
+        self.clock_freq
+    }
+
+    pub fn sample_freq(&self) -> u32 {
+        self.sample_freq
+    }
+
+    pub fn params(&self) -> ResampleParams {
+        self.params
+    }
+
+    fn matches(
+        &self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> bool {
+        self.method == method
+            && self.clock_freq == clock_freq
+            && self.sample_freq == sample_freq
+            && self.params == params
+    }
+}
+
+/// Cache of filter tables keyed by sampling method, frequencies and
+/// resampling parameters, for setting up many samplers without computing
+/// the same filters twice.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Default)]
+pub struct FirCache {
+    tables: Vec<FirTable>,
+}
+
+#[cfg(feature = "alloc")]
+impl FirCache {
+    pub fn new() -> Self {
+        FirCache { tables: Vec::new() }
+    }
+
+    /// Returns the cached table of a sampling configuration, computing it
+    /// on first use.
+    pub fn get(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<FirTable, Error> {
+        let cached = self
+            .tables
+            .iter()
+            .find(|table| table.matches(method, clock_freq, sample_freq, params));
+        if let Some(table) = cached {
+            return Ok(table.clone());
+        }
+        let table = FirTable::new(method, clock_freq, sample_freq, params)?;
+        self.tables.push(table.clone());
+        Ok(table)
+    }
+
+    /// Number of cached tables.
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    /// Drops all cached tables. Tables in use by samplers are kept alive by
+    /// their samplers.
+    pub fn clear(&mut self) {
+        self.tables.clear();
+    }
+}
+
+/// Sample history of `C` channels with a capacity of `R` samples. The last
+/// `size` samples of each channel are stored twice in a row, so that the
+/// history ending at any index is contiguous. With the `alloc` feature the
+/// ring is kept on the heap and sized to fit the filter, otherwise it is
+/// stored inline at its full capacity.
+#[derive(Clone)]
+struct Ring<const C: usize, const R: usize> {
+    #[cfg(feature = "alloc")]
+    data: Vec<i16>,
+    #[cfg(not(feature = "alloc"))]
+    data: [[[i16; R]; 2]; C],
+    size: usize,
+    index: usize,
+}
+
+impl<const C: usize, const R: usize> Ring<C, R> {
+    /// Largest ring size, `R` rounded down to a power of two and limited to
+    /// `RING_SIZE`.
+    const CAPACITY: usize = if R >= RING_SIZE {
+        RING_SIZE
+    } else if R == 0 {
+        0
+    } else {
+        1 << (usize::BITS - 1 - R.leading_zeros())
+    };
+
+    /// Creates an empty ring of `size` samples per channel, which is zero or
+    /// a power of two up to `CAPACITY`.
+    fn new(size: usize) -> Self {
+        Ring {
+            #[cfg(feature = "alloc")]
+            data: alloc::vec![0; size * 2 * C],
+            #[cfg(not(feature = "alloc"))]
+            data: [[[0; R]; 2]; C],
+            size,
+            index: 0,
+        }
+    }
+
+    /// Ring size of a filter and additional delay, holding one more sample
+    /// of history for the interpolation between adjacent filter tables.
+    fn size_for(fir: &Fir, delay: usize) -> usize {
+        ring_size(fir.n, delay)
+    }
+
+    #[inline]
+    fn channel(&self, ch: usize) -> &[i16] {
+        #[cfg(feature = "alloc")]
+        let channel = &self.data[ch * self.size * 2..(ch + 1) * self.size * 2];
+        #[cfg(not(feature = "alloc"))]
+        let channel = &self.data[ch].as_flattened()[..self.size * 2];
+        channel
+    }
+
+    #[inline]
+    fn channel_mut(&mut self, ch: usize) -> &mut [i16] {
+        #[cfg(feature = "alloc")]
+        let channel = &mut self.data[ch * self.size * 2..(ch + 1) * self.size * 2];
+        #[cfg(not(feature = "alloc"))]
+        let channel = &mut self.data[ch].as_flattened_mut()[..self.size * 2];
+        channel
+    }
+
+    #[inline]
+    fn push(&mut self, frame: [i16; C]) {
+        let (index, size) = (self.index, self.size);
+        for (ch, &sample) in frame.iter().enumerate() {
+            let channel = self.channel_mut(ch);
+            channel[index] = sample;
+            channel[index + size] = sample;
+        }
+        self.index = (index + 1) & (size - 1);
+    }
+
+    fn clear(&mut self) {
+        for ch in 0..C {
+            for sample in self.channel_mut(ch).iter_mut() {
+                *sample = 0;
+            }
+        }
+        self.index = 0;
+    }
+
+    /// Drops the history, keeping the inline storage.
+    fn truncate(&mut self) {
+        #[cfg(feature = "alloc")]
+        self.data.clear();
+        self.size = 0;
+        self.index = 0;
+    }
+
+    /// History of all channels, each in chronological order.
+    #[cfg(feature = "alloc")]
+    fn read(&self) -> Vec<i16> {
+        let mut history = Vec::with_capacity(self.size * C);
+        for ch in 0..C {
+            history.extend_from_slice(&self.channel(ch)[self.index..self.index + self.size]);
+        }
+        history
+    }
+
+    /// Restores history as returned by `read`. A longer history is cut to
+    /// its most recent samples and a shorter one is padded with silence.
+    fn write(&mut self, history: &[i16]) {
+        self.clear();
+        let len = history.len() / C;
+        let n = len.min(self.size);
+        let size = self.size;
+        for (ch, history) in history.chunks_exact(len.max(1)).take(C).enumerate() {
+            let history = &history[len - n..];
+            let channel = self.channel_mut(ch);
+            channel[size - n..size].copy_from_slice(history);
+            channel[size * 2 - n..].copy_from_slice(history);
+        }
+    }
+
+    /// Changes the ring size, keeping the most recent history. A larger ring
+    /// is padded with silence.
+    fn resize(&mut self, size: usize) {
+        let n = self.size.min(size);
+        let end = self.index + self.size;
+        #[cfg(feature = "alloc")]
+        {
+            let mut ring = Ring::new(size);
+            for ch in 0..C {
+                let history = &self.channel(ch)[end - n..end];
+                let channel = ring.channel_mut(ch);
+                channel[size - n..size].copy_from_slice(history);
+                channel[size * 2 - n..].copy_from_slice(history);
+            }
+            *self = ring;
+        }
+        // The inline storage holds any ring size, move the history in place.
+        #[cfg(not(feature = "alloc"))]
+        {
+            for channel in self.data.iter_mut() {
+                let channel = channel.as_flattened_mut();
+                channel.copy_within(end - n..end, size - n);
+                channel.copy_within(size - n..size, size * 2 - n);
+                for sample in channel[..size - n].iter_mut() {
+                    *sample = 0;
+                }
+                for sample in channel[size..size * 2 - n].iter_mut() {
+                    *sample = 0;
+                }
+            }
+            self.size = size;
+            self.index = 0;
+        }
+    }
+
+    /// Changes the ring size like `resize`, resampling the history to a
+    /// sample spacing of `ratio` times the current one by linear
+    /// interpolation.
+    fn resample(&mut self, size: usize, ratio: f64) {
+        #[cfg(feature = "alloc")]
+        {
+            let previous = core::mem::replace(self, Ring::new(size));
+            let end = previous.index + previous.size;
+            for ch in 0..C {
+                let history = &previous.channel(ch)[end - previous.size..end];
+                let channel = self.channel_mut(ch);
+                resample_history(history, &mut channel[..size], ratio);
+                channel.copy_within(..size, size);
+            }
+        }
+        // The inline storage holds two rings of any size, move the history
+        // to its end and resample it into the start.
+        #[cfg(not(feature = "alloc"))]
+        {
+            let (end, len) = (self.index + self.size, self.size);
+            for channel in self.data.iter_mut() {
+                let channel = channel.as_flattened_mut();
+                let top = channel.len();
+                channel.copy_within(end - len..end, top - len);
+                let (ring, history) = channel.split_at_mut(top - len);
+                resample_history(history, &mut ring[..size], ratio);
+                channel.copy_within(..size, size);
+            }
+            self.size = size;
+            self.index = 0;
+        }
+    }
+
+    fn rescale(&mut self, from_shift: i32, to_shift: i32) {
+        for ch in 0..C {
+            rescale(self.channel_mut(ch), from_shift, to_shift);
+        }
+    }
+}
+
+/// Second step of two-pass resampling, converting the intermediate
+/// frequency samples produced by the first step to the output frequency.
+/// The intermediate sample history is kept in `Sampler::two_pass_ring`.
+#[derive(Clone)]
+struct TwoPass {
+    fir: Fir,
+    /// Intermediate samples per output sample, 32.32 fixpoint.
+    samples_per_output: u64,
+    /// Sample offset of the next output sample, 0.32 fixpoint.
+    offset: u32,
+    /// Intermediate samples left to produce before the next output sample.
+    remaining: u32,
+}
+
+impl TwoPass {
+    fn reset(&mut self) {
+        self.offset = self.samples_per_output as u32;
+        self.remaining = (self.samples_per_output >> 32) as u32;
+    }
+}
+
+/// Audio source clocked by the sampler, producing a frame of `C` channels
+/// per cycle. Outputs are scaled to 16 bits, saturation is left to the
+/// sampler.
+pub trait Source<const C: usize> {
+    fn clock(&mut self);
+    fn clock_delta(&mut self, delta: u32);
+    fn output_frame(&self) -> [i32; C];
+    fn reset(&mut self);
+}
+
+impl Source<1> for Synth {
+    #[inline]
+    fn clock(&mut self) {
+        Synth::clock(self);
+    }
+
+    #[inline]
+    fn clock_delta(&mut self, delta: u32) {
+        Synth::clock_delta(self, delta);
+    }
+
+    #[inline]
+    fn output_frame(&self) -> [i32; 1] {
+        [self.output_raw()]
+    }
+
+    fn reset(&mut self) {
+        Synth::reset(self);
+    }
+}
+
+/// Output sample format produced by the sampler.
+pub trait Sample: Copy {
+    /// Headroom in bits kept by the resampling ring buffer.
+    const RING_SHIFT: i32;
+    /// Converts a 16-bit scaled source output.
+    fn from_output(output: i32) -> Self;
+    /// Interpolates between two source outputs, offset being a 16-bit fixpoint fraction.
+    fn from_interpolation(prev: i32, now: i32, offset: i32) -> Self;
+    /// Converts a FIR convolution result.
+    fn from_convolution(v: i32) -> Self;
+}
+
+/// 16-bit samples with saturation.
+impl Sample for i16 {
+    const RING_SHIFT: i32 = 0;
+
+    #[inline]
+    fn from_output(output: i32) -> Self {
+        saturate(output)
+    }
+
+    #[inline]
+    fn from_interpolation(prev: i32, now: i32, offset: i32) -> Self {
+        let prev = saturate(prev) as i32;
+        let now = saturate(now) as i32;
+        saturate(prev + ((offset as i64 * (now - prev) as i64) >> FIXP_SHIFT) as i32)
+    }
+
+    #[inline]
+    fn from_convolution(v: i32) -> Self {
+        // Saturated arithmetics to guard against 16 bit sample overflow.
+        saturate(v >> FIR_SHIFT)
+    }
+}
+
+/// Floating point samples normalized to 16-bit full scale, i.e. 1.0 is
+/// the level at which 16-bit samples saturate. No clipping is applied;
+/// resampling keeps one bit of headroom in the 16-bit ring buffer so its
+/// outputs span [-2.0, 2.0) at 15 bits of precision.
+impl Sample for f32 {
+    const RING_SHIFT: i32 = 1;
+
+    #[inline]
+    fn from_output(output: i32) -> Self {
+        output as f32 / OUTPUT_SCALE
+    }
+
+    #[inline]
+    fn from_interpolation(prev: i32, now: i32, offset: i32) -> Self {
+        let offset = offset as f32 / (1 << FIXP_SHIFT) as f32;
+        (prev as f32 + offset * (now - prev) as f32) / OUTPUT_SCALE
+    }
+
+    #[inline]
+    fn from_convolution(v: i32) -> Self {
+        v as f32 / (1 << (FIR_SHIFT - Self::RING_SHIFT)) as f32 / OUTPUT_SCALE
+    }
+}
+
+/// Convolution kernel used by the resampling methods.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Simd {
+    /// Portable scalar code.
+    Scalar,
+    /// x86 SSE4.2.
+    Sse42,
+    /// x86 AVX2.
+    Avx2,
+    /// AArch64 NEON.
+    Neon,
+    /// WebAssembly SIMD128, which can only be enabled at compile time with
+    /// `-C target-feature=+simd128`.
+    Simd128,
+}
+
+impl Simd {
+    /// Fastest kernel supported by the CPU. Without the `std` feature CPU
+    /// features cannot be detected at runtime, so only the target features
+    /// enabled at compile time are taken into account.
+    pub fn detect() -> Simd {
+        [Simd::Avx2, Simd::Sse42, Simd::Neon, Simd::Simd128]
+            .iter()
+            .cloned()
+            .find(|simd| simd.is_supported())
+            .unwrap_or(Simd::Scalar)
+    }
+
+    /// Checks whether the kernel can run on the CPU, see `detect`.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Simd::Scalar => true,
+            #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
+            Simd::Sse42 => alloc::is_x86_feature_detected!("sse4.2"),
+            #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
+            Simd::Avx2 => alloc::is_x86_feature_detected!("avx2"),
+            #[cfg(all(not(feature = "std"), any(target_arch = "x86", target_arch = "x86_64")))]
+            Simd::Sse42 => cfg!(target_feature = "sse4.2"),
+            #[cfg(all(not(feature = "std"), any(target_arch = "x86", target_arch = "x86_64")))]
+            Simd::Avx2 => cfg!(target_feature = "avx2"),
+            #[cfg(all(feature = "std", target_arch = "aarch64"))]
+            Simd::Neon => alloc::arch::is_aarch64_feature_detected!("neon"),
+            #[cfg(all(not(feature = "std"), target_arch = "aarch64"))]
+            Simd::Neon => cfg!(target_feature = "neon"),
+            #[cfg(target_arch = "wasm32")]
+            Simd::Simd128 => cfg!(target_feature = "simd128"),
+            _ => false,
+        }
+    }
+}
+
+/// Output level statistics gathered while sampling. Levels are measured
+/// in 16-bit units before saturation, so they may exceed full scale.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputStats {
+    /// Number of samples exceeding 16-bit full scale. The resampling
+    /// methods also count samples following source outputs which were
+    /// saturated on their way into the resampling filter.
+    pub clipped: u32,
+    /// Peak absolute sample level.
+    pub peak: u32,
+}
+
+/// Resampling history of a state snapshot with `C` channels, the rings in
+/// the format of `Sampler::read_ring`. Empty rings restore silence.
+pub(crate) struct History<'a, const C: usize> {
+    pub offset: i32,
+    pub prev_sample: [i32; C],
+    pub ring_shift: i32,
+    pub delay: u32,
+    pub ring: &'a [i16],
+    /// Sample offset, intermediate samples left and ring of the second step
+    /// of two-pass resampling, see `Sampler::read_two_pass`.
+    pub two_pass: (u32, u32, &'a [i16]),
+}
+
+impl<const C: usize> History<'_, C> {
+    /// Checks the fields independent of the sampling parameters.
+    pub fn validate(&self) -> Result<(), Error> {
+        check(
+            self.ring_shift == i16::RING_SHIFT || self.ring_shift == f32::RING_SHIFT,
+            "sampler_ring_shift",
+        )?;
+        check((self.delay as usize) < RING_SIZE, "sampler_delay")?;
+        // Ring history is sized to the filter of the sampling method and
+        // cut or padded to the current ring when restored.
+        check(self.ring.len() <= RING_SIZE * C, "sampler_ring")?;
+        check(
+            self.two_pass.2.len() <= RING_SIZE * C,
+            "sampler_two_pass_ring",
+        )
+    }
+}
+
+/// Sampler of a source with `C` channels, keeping a resampling history of
+/// up to `R` samples per channel, `F` filter table entries inline and a
+/// two-pass resampling history of up to `P` samples per channel, see
+/// `Sampler::with_capacity`.
 #[derive(Clone)]
-pub struct Sampler {
+pub struct Sampler<
+    S = Synth,
+    const C: usize = 1,
+    const R: usize = RING_SIZE,
+    const F: usize = 0,
+    const P: usize = TWO_PASS_RING_SIZE,
+> {
     // Dependencies
-    pub synth: Synth,
+    pub synth: S,
     // Configuration
     cycles_per_sample: u32,
-    #[cfg(feature = "alloc")]
+    /// Clock cycles per sample at the configured frequencies, before the
+    /// rate ratio is applied.
+    nominal_cycles_per_sample: u32,
+    rate_ratio: f64,
     fir: Fir,
+    two_pass: Option<TwoPass>,
+    /// Intermediate sample history of two-pass resampling.
+    two_pass_ring: Ring<C, P>,
+    /// Inline filter table storage, see `FirData::Inline`.
+    fir_storage: [i16; F],
     sampling_method: SamplingMethod,
-    #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
-    use_sse42: bool,
-    #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
-    use_avx2: bool,
+    simd: Simd,
     // Runtime State
-    buffer: [i16; RING_SIZE * 2],
-    index: usize,
-    offset: i32,
-    prev_sample: i16,
+    ring: Ring<C, R>,
+    /// Delay of the history read by the output filter in samples of its
+    /// input on top of the filter latency, keeping the latency across
+    /// seamless parameter changes.
+    pub(crate) delay: usize,
+    pub(crate) offset: i32,
+    pub(crate) prev_sample: [i32; C],
+    pub(crate) ring_shift: i32,
+    /// Whether a source output was saturated by the ring buffer since the
+    /// last sample, per channel.
+    ring_clipped: [bool; C],
+    stats: OutputStats,
+}
+
+impl<S: Source<C>, const C: usize> Sampler<S, C> {
+    pub fn new(synth: S) -> Self {
+        Sampler::with_capacity(synth)
+    }
 }
 
-impl Sampler {
-    pub fn new(synth: Synth) -> Self {
+impl<S: Source<C>, const C: usize, const R: usize, const F: usize, const P: usize>
+    Sampler<S, C, R, F, P>
+{
+    /// Creates a sampler with a ring capacity of `R` samples per channel,
+    /// rounded down to a power of two and limited to `RING_SIZE`. Resampling
+    /// filters which do not fit are rejected. Only with the `alloc` feature
+    /// the ring is kept on the heap and sized to the filter in use, without
+    /// it the ring is stored inline at its full capacity, so e.g. a sampler
+    /// limited to `Fast` and `Interpolate` is best given a capacity of zero.
+    /// `ResampleTwoPass` keeps a second ring of intermediate samples with a
+    /// capacity of `P` samples per channel, which a sampler not using it is
+    /// best given a capacity of zero as well.
+    ///
+    /// The sampler also stores up to `F` filter table entries inline, which
+    /// makes the resampling methods available without the `alloc` feature,
+    /// see `fir_table_len` and `set_parameters_inline`.
+    pub fn with_capacity(synth: S) -> Self {
         Sampler {
             synth,
             cycles_per_sample: 0,
-            #[cfg(feature = "alloc")]
+            nominal_cycles_per_sample: 0,
+            rate_ratio: 1.0,
             fir: Fir {
-                data: Vec::new(),
+                data: FirData::Inline(0),
                 n: 0,
                 res: 0,
             },
+            two_pass: None,
+            two_pass_ring: Ring::new(0),
+            fir_storage: [0; F],
             sampling_method: SamplingMethod::Fast,
-            #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
-            use_avx2: alloc::is_x86_feature_detected!("avx2"),
-            #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
-            use_sse42: alloc::is_x86_feature_detected!("sse4.2"),
-            buffer: [0; RING_SIZE * 2],
-            index: 0,
+            simd: Simd::detect(),
+            ring: Ring::new(0),
+            delay: 0,
             offset: 0,
-            prev_sample: 0,
+            prev_sample: [0; C],
+            ring_shift: 0,
+            ring_clipped: [false; C],
+            stats: OutputStats::default(),
+        }
+    }
+
+    /// Sets the sampling method and frequencies. The parameters are checked
+    /// first and left unchanged if they are not supported.
+    pub fn set_parameters(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+    ) -> Result<(), Error> {
+        self.set_parameters_with(method, clock_freq, sample_freq, ResampleParams::default())
+    }
+
+    /// Sets the sampling method and frequencies along with the resampling
+    /// filter configuration, which is ignored by non-resampling methods.
+    /// Without the `alloc` feature the filter tables are stored inline, see
+    /// `set_parameters_inline`.
+    pub fn set_parameters_with(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<(), Error> {
+        let inline = !cfg!(feature = "alloc");
+        self.configure(method, clock_freq, sample_freq, params, inline, false)
+    }
+
+    /// Sets the sampling parameters like `set_parameters_with`, storing the
+    /// filter tables in the inline storage of the sampler instead of on the
+    /// heap. Fails with `Error::BufferTooSmall` if the storage holds fewer
+    /// than `fir_table_len` entries.
+    pub fn set_parameters_inline(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<(), Error> {
+        self.configure(method, clock_freq, sample_freq, params, true, false)
+    }
+
+    /// Sets the sampling parameters of a precomputed filter table, sharing
+    /// the table instead of computing the filters.
+    #[cfg(feature = "alloc")]
+    pub fn set_parameters_shared(&mut self, table: &FirTable) -> Result<(), Error> {
+        Self::check_capacity(&table.filters)?;
+        self.apply(table.method, &table.filters, false);
+        Ok(())
+    }
+
+    /// Changes the sampling parameters like `set_parameters_with` while
+    /// keeping the sample history and offset, so that the output continues
+    /// without a dropout, e.g. when the audio device switches to another
+    /// sample frequency. History is only recorded by the resampling methods
+    /// and carried over between methods sharing their ring buffers.
+    ///
+    /// The history is delayed to keep the latency of the previous filters
+    /// if the new ones are shorter. Longer filters cannot be compensated,
+    /// the output then steps back by the latency difference, see
+    /// `filter_latency`.
+    pub fn change_parameters(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<(), Error> {
+        let inline = !cfg!(feature = "alloc");
+        self.configure(method, clock_freq, sample_freq, params, inline, true)
+    }
+
+    fn configure(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+        inline: bool,
+        keep_history: bool,
+    ) -> Result<(), Error> {
+        let storage = if inline {
+            Some(&mut self.fir_storage[..])
+        } else {
+            None
+        };
+        let filters = Filters::new(
+            method,
+            clock_freq,
+            sample_freq,
+            params,
+            Ring::<C, R>::CAPACITY,
+            Ring::<C, P>::CAPACITY,
+            storage,
+        )?;
+        self.apply(method, &filters, keep_history);
+        Ok(())
+    }
+
+    /// Checks that the ring capacity can hold the history of the filters.
+    #[cfg(feature = "alloc")]
+    fn check_capacity(filters: &Filters) -> Result<(), Error> {
+        if let Some((fir, _)) = filters.two_pass.as_ref() {
+            if Ring::<C, P>::size_for(fir, 0) > Ring::<C, P>::CAPACITY {
+                return Err(Error::BufferTooSmall);
+            }
+        }
+        if Ring::<C, R>::size_for(&filters.fir, 0) <= Ring::<C, R>::CAPACITY {
+            Ok(())
+        } else {
+            Err(Error::BufferTooSmall)
+        }
+    }
+
+    fn apply(&mut self, method: SamplingMethod, filters: &Filters, keep_history: bool) {
+        let latency = self.latency() as usize;
+        let (previous, from) = (
+            self.two_pass.take().filter(|_| keep_history),
+            self.cycles_per_sample,
+        );
+        self.fir = filters.fir.clone();
+        self.two_pass = filters.two_pass.as_ref().map(|(fir, samples_per_output)| {
+            let mut two_pass = TwoPass {
+                fir: fir.clone(),
+                samples_per_output: *samples_per_output,
+                offset: 0,
+                remaining: 0,
+            };
+            two_pass.reset();
+            two_pass
+        });
+        self.nominal_cycles_per_sample = filters.cycles_per_sample;
+        self.cycles_per_sample = self.adjusted_cycles_per_sample();
+        self.sampling_method = method;
+
+        // Delay the history by the latency the new filters lack, so that
+        // the output does not skip ahead in time.
+        self.delay = 0;
+        if keep_history {
+            let delay = latency.saturating_sub(self.filter_latency());
+            self.delay = self.delay_samples(delay).min(self.max_delay());
+            if !self.is_valid_offset(self.offset) {
+                self.offset = 0;
+            }
+        } else {
+            // Clear state
+            self.ring.truncate();
+            self.offset = 0;
+            self.prev_sample = [0; C];
+            self.ring_clipped = [false; C];
+        }
+        if let Some(two_pass) = self.two_pass.as_mut() {
+            self.ring.resize(Ring::<C, R>::size_for(&self.fir, 0));
+            let size = Ring::<C, P>::size_for(&two_pass.fir, self.delay);
+            match previous {
+                // The intermediate frequency follows the sample frequency,
+                // carry over the history and the time to the next output
+                // sample at the new intermediate frequency.
+                Some(previous) => {
+                    let to = self.cycles_per_sample;
+                    self.two_pass_ring.resample(size, to as f64 / from as f64);
+                    let remaining = previous.remaining as u64 * from as u64 / to as u64;
+                    let max = (two_pass.samples_per_output >> 32) as u32 + 1;
+                    two_pass.remaining = (remaining as u32).clamp(1, max);
+                    two_pass.offset = previous.offset;
+                }
+                None => {
+                    self.two_pass_ring.truncate();
+                    self.two_pass_ring.resize(size);
+                }
+            }
+            return;
+        }
+        self.two_pass_ring.truncate();
+        self.ring
+            .resize(Ring::<C, R>::size_for(&self.fir, self.delay));
+    }
+
+    /// Delay of the output behind the source in cycles, introduced by the
+    /// resampling filters and the history delay kept by `change_parameters`.
+    pub fn latency(&self) -> u32 {
+        (self.filter_latency() + self.delay_cycles()) as u32
+    }
+
+    /// Latency of the resampling filters in cycles, i.e. half their length.
+    fn filter_latency(&self) -> usize {
+        let latency = self.fir.n as usize / 2;
+        if let Some(two_pass) = self.two_pass.as_ref() {
+            return latency + self.delay_cycles_of(two_pass.fir.n as usize / 2);
+        }
+        latency
+    }
+
+    /// Latency added by the history delay in cycles. The delay applies to
+    /// the input of the filter computing the output samples, i.e. it counts
+    /// intermediate samples with two-pass resampling.
+    fn delay_cycles(&self) -> usize {
+        self.delay_cycles_of(self.delay)
+    }
+
+    fn delay_cycles_of(&self, delay: usize) -> usize {
+        if self.two_pass.is_some() {
+            return ((delay as u64 * self.cycles_per_sample as u64) >> FIXP_SHIFT) as usize;
+        }
+        delay
+    }
+
+    /// Converts a delay in cycles to the unit of `delay`.
+    fn delay_samples(&self, cycles: usize) -> usize {
+        if self.two_pass.is_some() {
+            return ((cycles as u64) << FIXP_SHIFT) as usize / self.cycles_per_sample as usize;
+        }
+        cycles
+    }
+
+    /// Longest history delay the ring can hold with the current filters.
+    fn max_delay(&self) -> usize {
+        if let Some(two_pass) = self.two_pass.as_ref() {
+            return Ring::<C, P>::CAPACITY - two_pass.fir.n as usize - 1;
+        }
+        if self.fir.n == 0 {
+            0
+        } else {
+            Ring::<C, R>::CAPACITY - self.fir.n as usize - 1
+        }
+    }
+
+    /// Checks whether a history delay fits the ring with the current
+    /// sampling parameters.
+    fn is_valid_delay(&self, delay: usize) -> bool {
+        delay <= self.max_delay()
+    }
+
+    /// Restores the history delay, resizing the ring to hold it.
+    fn write_delay(&mut self, delay: usize) {
+        self.delay = delay;
+        if let Some(two_pass) = self.two_pass.as_ref() {
+            self.two_pass_ring
+                .resize(Ring::<C, P>::size_for(&two_pass.fir, delay));
+            return;
         }
+        self.ring.resize(Ring::<C, R>::size_for(&self.fir, delay));
+    }
+
+    pub fn reset(&mut self) {
+        self.synth.reset();
+        self.ring.index = 0;
+        self.offset = 0;
+        self.prev_sample = [0; C];
+        self.ring_clipped = [false; C];
+        self.stats = OutputStats::default();
+        self.reset_two_pass();
+    }
+
+    fn reset_two_pass(&mut self) {
+        if let Some(two_pass) = self.two_pass.as_mut() {
+            two_pass.reset();
+            self.two_pass_ring.clear();
+        }
+    }
+
+    /// Checks whether a sample offset can be reached with the current
+    /// sampling parameters.
+    fn is_valid_offset(&self, offset: i32) -> bool {
+        let min = -(self.cycles_per_sample as i32) - (1 << FIXP_SHIFT);
+        offset > min && offset < 1 << FIXP_SHIFT
+    }
+
+    /// Whether the sampling method keeps a ring buffer history.
+    pub(crate) fn has_history(&self) -> bool {
+        self.ring.size != 0 || self.two_pass.is_some()
+    }
+
+    /// Ring buffer history of all channels, each in chronological order.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn read_ring(&self) -> Vec<i16> {
+        self.ring.read()
     }
 
-    pub fn set_parameters(&mut self, method: SamplingMethod, clock_freq: u32, sample_freq: u32) {
-        self.cycles_per_sample =
-            (clock_freq as f64 / sample_freq as f64 * (1 << FIXP_SHIFT) as f64 + 0.5) as u32;
-        self.sampling_method = method;
+    /// Restores ring buffer history as returned by `read_ring`, an empty
+    /// history clears the ring. The history may be of a different ring
+    /// size, see `Ring::write`.
+    fn write_ring(&mut self, ring: &[i16]) {
+        self.ring.write(ring);
+    }
 
-        #[cfg(feature = "alloc")]
-        if self.sampling_method == SamplingMethod::Resample
-            || self.sampling_method == SamplingMethod::ResampleFast
-        {
-            self.init_fir(clock_freq as f64, sample_freq as f64, -1.0, 0.97);
+    /// Second step state of two-pass resampling as the sample offset,
+    /// intermediate samples left and ring buffer history in the format of
+    /// `read_ring`. All empty for other sampling methods.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn read_two_pass(&self) -> (u32, u32, Vec<i16>) {
+        match self.two_pass.as_ref() {
+            Some(two_pass) => (
+                two_pass.offset,
+                two_pass.remaining,
+                self.two_pass_ring.read(),
+            ),
+            None => (0, 0, Vec::new()),
         }
-        // Clear state
-        for j in 0..RING_SIZE * 2 {
-            self.buffer[j] = 0;
+    }
+
+    /// Checks whether a count of intermediate samples left can be reached
+    /// with the current sampling parameters.
+    fn is_valid_two_pass(&self, remaining: u32) -> bool {
+        match self.two_pass.as_ref() {
+            Some(two_pass) => remaining <= (two_pass.samples_per_output >> 32) as u32 + 1,
+            None => true,
         }
-        self.index = 0;
-        self.offset = 0;
-        self.prev_sample = 0;
     }
 
-    pub fn reset(&mut self) {
-        self.synth.reset();
-        self.index = 0;
-        self.offset = 0;
-        self.prev_sample = 0;
+    /// Restores second step state as returned by `read_two_pass`, zero
+    /// intermediate samples left restarts the second step. Ignored for
+    /// other sampling methods.
+    fn write_two_pass(&mut self, offset: u32, remaining: u32, ring: &[i16]) {
+        self.reset_two_pass();
+        if let Some(two_pass) = self.two_pass.as_mut() {
+            if remaining == 0 {
+                return;
+            }
+            two_pass.offset = offset;
+            two_pass.remaining = remaining;
+            self.two_pass_ring.write(ring);
+        }
+    }
+
+    /// Checks that a resampling history can be restored with the current
+    /// sampling parameters, see `History::validate` for the checks that do
+    /// not depend on them.
+    pub(crate) fn check_history(&self, history: &History<'_, C>) -> Result<(), Error> {
+        check(self.is_valid_offset(history.offset), "sampler_offset")?;
+        check(self.is_valid_delay(history.delay as usize), "sampler_delay")?;
+        check(
+            self.is_valid_two_pass(history.two_pass.1),
+            "sampler_two_pass_remaining",
+        )
+    }
+
+    /// Restores a resampling history accepted by `check_history`.
+    pub(crate) fn write_history(&mut self, history: &History<'_, C>) {
+        self.offset = history.offset;
+        self.prev_sample = history.prev_sample;
+        self.ring_shift = history.ring_shift;
+        self.write_delay(history.delay as usize);
+        self.write_ring(history.ring);
+        let (offset, remaining, ring) = history.two_pass;
+        self.write_two_pass(offset, remaining, ring);
+    }
+
+    /// Output level statistics since the last call to `reset_stats`.
+    pub fn stats(&self) -> OutputStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = OutputStats::default();
     }
 
+    /// Clocks the source while producing samples in the format of the
+    /// output buffer, i.e. 16-bit saturated or normalized floating point.
     #[inline]
-    pub fn clock(&mut self, delta: u32, buffer: &mut [i16], interleave: usize) -> (usize, u32) {
+    pub fn clock<T: Sample>(
+        &mut self,
+        delta: u32,
+        buffer: &mut [T],
+        interleave: usize,
+    ) -> (usize, u32) {
+        if self.ring_shift != T::RING_SHIFT {
+            self.set_ring_shift(T::RING_SHIFT);
+        }
         match self.sampling_method {
             SamplingMethod::Fast => self.clock_fast(delta, buffer, interleave),
             SamplingMethod::Interpolate => self.clock_interpolate(delta, buffer, interleave),
-            #[cfg(feature = "alloc")]
             SamplingMethod::Resample => self.clock_resample_interpolate(delta, buffer, interleave),
-            #[cfg(feature = "alloc")]
             SamplingMethod::ResampleFast => self.clock_resample_fast(delta, buffer, interleave),
+            SamplingMethod::ResampleTwoPass => {
+                self.clock_resample_two_pass(delta, buffer, interleave)
+            }
         }
     }
 
     /// SID clocking with audio sampling - delta clocking picking nearest sample.
     #[inline]
-    fn clock_fast(
+    fn clock_fast<T: Sample>(
         &mut self,
         mut delta: u32,
-        buffer: &mut [i16],
+        buffer: &mut [T],
         interleave: usize,
     ) -> (usize, u32) {
         let mut index = 0;
         loop {
             let next_sample_offset = self.get_next_sample_offset();
             let delta_sample = (next_sample_offset >> FIXP_SHIFT) as u32;
-            if delta_sample > delta || index >= buffer.len() {
+            if delta_sample > delta || !has_room::<C>(index, buffer.len(), interleave) {
                 break;
             }
             self.synth.clock_delta(delta_sample);
             delta -= delta_sample;
-            buffer[(index * interleave) as usize] = self.synth.output();
+            let frame = self.synth.output_frame();
+            for ch in 0..C {
+                self.record_level(frame[ch]);
+                buffer[index * interleave + ch] = T::from_output(frame[ch]);
+            }
             index += 1;
             self.update_sample_offset(next_sample_offset);
         }
-        if delta > 0 && index < buffer.len() {
+        if delta > 0 && has_room::<C>(index, buffer.len(), interleave) {
             self.synth.clock_delta(delta);
             self.offset -= (delta as i32) << FIXP_SHIFT;
             (index, 0)
@@ -163,35 +1212,43 @@ impl Sampler {
     }
 
     #[inline]
-    fn clock_interpolate(
+    fn clock_interpolate<T: Sample>(
         &mut self,
         mut delta: u32,
-        buffer: &mut [i16],
+        buffer: &mut [T],
         interleave: usize,
     ) -> (usize, u32) {
         let mut index = 0;
         loop {
             let next_sample_offset = self.get_next_sample_offset();
             let delta_sample = (next_sample_offset >> FIXP_SHIFT) as u32;
-            if delta_sample > delta || index >= buffer.len() {
+            if delta_sample > delta || !has_room::<C>(index, buffer.len(), interleave) {
                 break;
             }
             for _i in 0..(delta_sample - 1) {
-                self.prev_sample = self.synth.output();
                 self.synth.clock();
             }
+            self.prev_sample = self.synth.output_frame();
+            self.synth.clock();
             delta -= delta_sample;
-            let sample_now = self.synth.output();
-            buffer[index * interleave] = self.prev_sample
-                + ((self.offset * (sample_now - self.prev_sample) as i32) >> FIXP_SHIFT) as i16;
+            let sample_now = self.synth.output_frame();
+            for ch in 0..C {
+                let (prev, now) = (self.prev_sample[ch], sample_now[ch]);
+                self.record_level(
+                    prev + ((self.offset as i64 * (now - prev) as i64) >> FIXP_SHIFT) as i32,
+                );
+                buffer[index * interleave + ch] = T::from_interpolation(prev, now, self.offset);
+            }
             index += 1;
             self.prev_sample = sample_now;
             self.update_sample_offset(next_sample_offset);
         }
-        if delta > 0 && index < buffer.len() {
+        if delta > 0 && has_room::<C>(index, buffer.len(), interleave) {
             for _i in 0..(delta - 1) {
                 self.synth.clock();
             }
+            self.prev_sample = self.synth.output_frame();
+            self.synth.clock();
             self.offset -= (delta as i32) << FIXP_SHIFT;
             (index, 0)
         } else {
@@ -233,88 +1290,184 @@ impl Sampler {
     ///
     /// NB! the result of right shifting negative numbers is really
     /// implementation dependent in the C++ standard.
-    #[cfg(feature = "alloc")]
     #[inline]
-    fn clock_resample_interpolate(
+    fn clock_resample_interpolate<T: Sample>(
         &mut self,
         mut delta: u32,
-        buffer: &mut [i16],
+        buffer: &mut [T],
         interleave: usize,
     ) -> (usize, u32) {
         let mut index = 0;
-        let half = 1i32 << 15;
         loop {
             let next_sample_offset = self.get_next_sample_offset2();
             let delta_sample = (next_sample_offset >> FIXP_SHIFT) as u32;
-            if delta_sample > delta || index >= buffer.len() {
+            if delta_sample > delta || !has_room::<C>(index, buffer.len(), interleave) {
                 break;
             }
 
             for _i in 0..delta_sample {
-                self.synth.clock();
-                let output = self.synth.output();
-                self.buffer[self.index] = output;
-                self.buffer[self.index + RING_SIZE] = output;
-                self.index += 1;
-                self.index &= 0x3fff;
+                self.clock_ring();
             }
             delta -= delta_sample;
             self.update_sample_offset2(next_sample_offset);
 
-            let fir_offset_1 = (self.offset * self.fir.res) >> FIXP_SHIFT;
-            let fir_offset_rmd = (self.offset * self.fir.res) & FIXP_MASK;
-            let fir_start_1 = (fir_offset_1 * self.fir.n) as usize;
-            let fir_end_1 = fir_start_1 + self.fir.n as usize;
-            let sample_start_1 = (self.index as i32 - self.fir.n + RING_SIZE as i32) as usize;
-            let sample_end_1 = sample_start_1 + self.fir.n as usize;
-
-            // Convolution with filter impulse response.
-            let v1 = self.compute_convolution_fir(
-                &self.buffer[sample_start_1..sample_end_1],
-                &self.fir.data[fir_start_1..fir_end_1],
-            );
-
-            // Use next FIR table, wrap around to first FIR table using
-            // previous sample.
-            let mut fir_offset_2 = fir_offset_1 + 1;
-            let mut sample_start_2 = sample_start_1;
-            if fir_offset_2 == self.fir.res {
-                fir_offset_2 = 0;
-                sample_start_2 -= 1;
-            }
-            let fir_start_2 = (fir_offset_2 * self.fir.n) as usize;
-            let fir_end_2 = fir_start_2 + self.fir.n as usize;
-            let sample_end_2 = sample_start_2 + self.fir.n as usize;
-
-            let v2 = self.compute_convolution_fir(
-                &self.buffer[sample_start_2..sample_end_2],
-                &self.fir.data[fir_start_2..fir_end_2],
-            );
-
-            // Linear interpolation.
-            // fir_offset_rmd is equal for all samples, it can thus be factorized out:
-            // sum(v1 + rmd*(v2 - v1)) = sum(v1) + rmd*(sum(v2) - sum(v1))
-            let mut v = v1 + ((fir_offset_rmd * (v2 - v1)) >> FIXP_SHIFT);
-            v >>= FIR_SHIFT;
-
-            // Saturated arithmetics to guard against 16 bit sample overflow.
-            if v >= half {
-                v = half - 1;
-            } else if v < -half {
-                v = -half;
-            }
-
-            buffer[index * interleave] = v as i16;
+            for ch in 0..C {
+                let v = self.compute_convolution_interpolated(
+                    self.ring.channel(ch),
+                    self.ring.index + self.ring.size - self.delay,
+                    &self.fir,
+                    self.offset,
+                );
+                self.record_sample(ch, v >> (FIR_SHIFT - T::RING_SHIFT));
+                buffer[index * interleave + ch] = T::from_convolution(v);
+            }
             index += 1;
         }
-        if delta > 0 && index < buffer.len() {
+        if delta > 0 && has_room::<C>(index, buffer.len(), interleave) {
             for _i in 0..delta {
-                self.synth.clock();
-                let output = self.synth.output();
-                self.buffer[self.index] = output;
-                self.buffer[self.index + RING_SIZE] = output;
-                self.index += 1;
-                self.index &= 0x3fff;
+                self.clock_ring();
+            }
+            self.offset -= (delta as i32) << FIXP_SHIFT;
+            (index, 0)
+        } else {
+            (index, delta)
+        }
+    }
+
+    /// Filter table of a filter of the sampler.
+    #[inline]
+    fn fir_data<'a>(&'a self, fir: &'a Fir) -> &'a [i16] {
+        match &fir.data {
+            #[cfg(feature = "alloc")]
+            FirData::Shared(data) => data,
+            FirData::Inline(start) => {
+                &self.fir_storage[*start..*start + (fir.n * fir.res) as usize]
+            }
+        }
+    }
+
+    /// Convolution of the ring buffer history ending before `end` with the
+    /// filter impulse response at sample offset `offset`, linearly
+    /// interpolated between adjacent FIR tables.
+    #[inline]
+    fn compute_convolution_interpolated(
+        &self,
+        ring: &[i16],
+        end: usize,
+        fir: &Fir,
+        offset: i32,
+    ) -> i32 {
+        let fir_offset_1 = (offset * fir.res) >> FIXP_SHIFT;
+        let fir_offset_rmd = (offset * fir.res) & FIXP_MASK;
+        let fir_start_1 = (fir_offset_1 * fir.n) as usize;
+        let fir_end_1 = fir_start_1 + fir.n as usize;
+        let sample_start_1 = end - fir.n as usize;
+        let sample_end_1 = sample_start_1 + fir.n as usize;
+
+        // Use next FIR table, wrap around to first FIR table using
+        // previous sample.
+        let mut fir_offset_2 = fir_offset_1 + 1;
+        let mut sample_start_2 = sample_start_1;
+        if fir_offset_2 == fir.res {
+            fir_offset_2 = 0;
+            sample_start_2 -= 1;
+        }
+        let fir_start_2 = (fir_offset_2 * fir.n) as usize;
+        let fir_end_2 = fir_start_2 + fir.n as usize;
+        let sample_end_2 = sample_start_2 + fir.n as usize;
+
+        // Convolution with filter impulse response.
+        let fir_data = self.fir_data(fir);
+        let v1 = self.compute_convolution_fir(
+            &ring[sample_start_1..sample_end_1],
+            &fir_data[fir_start_1..fir_end_1],
+        );
+        let v2 = self.compute_convolution_fir(
+            &ring[sample_start_2..sample_end_2],
+            &fir_data[fir_start_2..fir_end_2],
+        );
+
+        // Linear interpolation.
+        // fir_offset_rmd is equal for all samples, it can thus be factorized out:
+        // sum(v1 + rmd*(v2 - v1)) = sum(v1) + rmd*(sum(v2) - sum(v1))
+        v1 + ((fir_offset_rmd as i64 * (v2 - v1) as i64) >> FIXP_SHIFT) as i32
+    }
+
+    /// SID clocking with audio sampling - cycle based with two-pass audio
+    /// resampling.
+    ///
+    /// The source is first resampled to Laurent Ganier's optimal intermediate
+    /// frequency, see `clock_resample_interpolate`, and the intermediate
+    /// samples are then resampled to the sampling frequency. Both steps
+    /// allow aliasing into their transition band, which keeps the wide
+    /// transition band of the first step and yields filters of a fraction of
+    /// the length of the single step filter.
+    #[inline]
+    fn clock_resample_two_pass<T: Sample>(
+        &mut self,
+        mut delta: u32,
+        buffer: &mut [T],
+        interleave: usize,
+    ) -> (usize, u32) {
+        // The second step is set up along with the sampling method. It is
+        // taken out while clocking the first step, which borrows the sampler.
+        let mut two_pass = match self.two_pass.take() {
+            Some(two_pass) => two_pass,
+            None => return (0, delta),
+        };
+        let mut index = 0;
+        loop {
+            let next_sample_offset = self.get_next_sample_offset2();
+            let delta_sample = (next_sample_offset >> FIXP_SHIFT) as u32;
+            // An intermediate sample completing an output sample needs room
+            // for the output.
+            if delta_sample > delta
+                || (two_pass.remaining == 1 && !has_room::<C>(index, buffer.len(), interleave))
+            {
+                break;
+            }
+
+            for _i in 0..delta_sample {
+                self.clock_ring();
+            }
+            delta -= delta_sample;
+            self.update_sample_offset2(next_sample_offset);
+
+            let mut frame = [0; C];
+            for (ch, sample) in frame.iter_mut().enumerate() {
+                let v = self.compute_convolution_interpolated(
+                    self.ring.channel(ch),
+                    self.ring.index + self.ring.size,
+                    &self.fir,
+                    self.offset,
+                );
+                *sample = self.saturate_ring(ch, v >> FIR_SHIFT);
+            }
+            self.two_pass_ring.push(frame);
+
+            two_pass.remaining -= 1;
+            if two_pass.remaining == 0 {
+                let offset = (two_pass.offset >> FIXP_SHIFT) as i32;
+                for ch in 0..C {
+                    let v = self.compute_convolution_interpolated(
+                        self.two_pass_ring.channel(ch),
+                        self.two_pass_ring.index + self.two_pass_ring.size - self.delay,
+                        &two_pass.fir,
+                        offset,
+                    );
+                    self.record_sample(ch, v >> (FIR_SHIFT - T::RING_SHIFT));
+                    buffer[index * interleave + ch] = T::from_convolution(v);
+                }
+                index += 1;
+                let next = two_pass.offset as u64 + two_pass.samples_per_output;
+                two_pass.offset = next as u32;
+                two_pass.remaining = (next >> 32) as u32;
+            }
+        }
+        self.two_pass = Some(two_pass);
+        if delta > 0 && has_room::<C>(index, buffer.len(), interleave) {
+            for _i in 0..delta {
+                self.clock_ring();
             }
             self.offset -= (delta as i32) << FIXP_SHIFT;
             (index, 0)
@@ -324,30 +1477,23 @@ impl Sampler {
     }
 
     /// SID clocking with audio sampling - cycle based with audio resampling.
-    #[cfg(feature = "alloc")]
     #[inline]
-    fn clock_resample_fast(
+    fn clock_resample_fast<T: Sample>(
         &mut self,
         mut delta: u32,
-        buffer: &mut [i16],
+        buffer: &mut [T],
         interleave: usize,
     ) -> (usize, u32) {
         let mut index = 0;
-        let half = 1i32 << 15;
         loop {
             let next_sample_offset = self.get_next_sample_offset2();
             let delta_sample = (next_sample_offset >> FIXP_SHIFT) as u32;
-            if delta_sample > delta || index >= buffer.len() {
+            if delta_sample > delta || !has_room::<C>(index, buffer.len(), interleave) {
                 break;
             }
 
             for _i in 0..delta_sample {
-                self.synth.clock();
-                let output = self.synth.output();
-                self.buffer[self.index] = output;
-                self.buffer[self.index + RING_SIZE] = output;
-                self.index += 1;
-                self.index &= 0x3fff;
+                self.clock_ring();
             }
             delta -= delta_sample;
             self.update_sample_offset2(next_sample_offset);
@@ -355,34 +1501,24 @@ impl Sampler {
             let fir_offset = (self.offset * self.fir.res) >> FIXP_SHIFT;
             let fir_start = (fir_offset * self.fir.n) as usize;
             let fir_end = fir_start + self.fir.n as usize;
-            let sample_start = (self.index as i32 - self.fir.n + RING_SIZE as i32) as usize;
+            let sample_start = self.ring.index + self.ring.size - self.delay - self.fir.n as usize;
             let sample_end = sample_start + self.fir.n as usize;
 
-            // Convolution with filter impulse response.
-            let mut v = self.compute_convolution_fir(
-                &self.buffer[sample_start..sample_end],
-                &self.fir.data[fir_start..fir_end],
-            );
-            v >>= FIR_SHIFT;
+            for ch in 0..C {
+                // Convolution with filter impulse response.
+                let v = self.compute_convolution_fir(
+                    &self.ring.channel(ch)[sample_start..sample_end],
+                    &self.fir_data(&self.fir)[fir_start..fir_end],
+                );
 
-            // Saturated arithmetics to guard against 16 bit sample overflow.
-            if v >= half {
-                v = half - 1;
-            } else if v < -half {
-                v = -half;
+                self.record_sample(ch, v >> (FIR_SHIFT - T::RING_SHIFT));
+                buffer[index * interleave + ch] = T::from_convolution(v);
             }
-
-            buffer[index * interleave] = v as i16;
             index += 1;
         }
-        if delta > 0 && index < buffer.len() {
+        if delta > 0 && has_room::<C>(index, buffer.len(), interleave) {
             for _i in 0..delta {
-                self.synth.clock();
-                let output = self.synth.output();
-                self.buffer[self.index] = output;
-                self.buffer[self.index + RING_SIZE] = output;
-                self.index += 1;
-                self.index &= 0x3fff;
+                self.clock_ring();
             }
             self.offset -= (delta as i32) << FIXP_SHIFT;
             (index, 0)
@@ -391,30 +1527,126 @@ impl Sampler {
         }
     }
 
+    /// Clocks the source one cycle and pushes its output into the ring buffer.
+    #[inline]
+    fn clock_ring(&mut self) {
+        self.synth.clock();
+        let frame = self.synth.output_frame();
+        let mut samples = [0; C];
+        for (ch, sample) in samples.iter_mut().enumerate() {
+            *sample = self.saturate_ring(ch, frame[ch] >> self.ring_shift);
+        }
+        self.ring.push(samples);
+    }
+
+    /// Saturates a sample written to a ring buffer, noting the clipping for
+    /// the output statistics.
+    #[inline]
+    fn saturate_ring(&mut self, ch: usize, sample: i32) -> i16 {
+        if !(-OUTPUT_HALF..OUTPUT_HALF).contains(&sample) {
+            self.ring_clipped[ch] = true;
+        }
+        saturate(sample)
+    }
+
+    /// Records the level of a resampled output sample, counting it as
+    /// clipped if its input was saturated by the ring buffer.
+    #[inline]
+    fn record_sample(&mut self, ch: usize, level: i32) {
+        let ring_clipped = core::mem::replace(&mut self.ring_clipped[ch], false);
+        if ring_clipped && (-OUTPUT_HALF..OUTPUT_HALF).contains(&level) {
+            self.stats.clipped += 1;
+        }
+        self.record_level(level);
+    }
+
+    #[inline]
+    fn record_level(&mut self, level: i32) {
+        if !(-OUTPUT_HALF..OUTPUT_HALF).contains(&level) {
+            self.stats.clipped += 1;
+        }
+        let level = level.unsigned_abs();
+        if level > self.stats.peak {
+            self.stats.peak = level;
+        }
+    }
+
+    /// Rescales the ring buffer history when switching between output
+    /// formats with different headroom.
+    fn set_ring_shift(&mut self, ring_shift: i32) {
+        self.ring.rescale(self.ring_shift, ring_shift);
+        self.two_pass_ring.rescale(self.ring_shift, ring_shift);
+        self.ring_shift = ring_shift;
+    }
+
+    /// Ratio of the actual to the configured sample frequency.
+    pub fn rate_ratio(&self) -> f64 {
+        self.rate_ratio
+    }
+
+    /// Adjusts the sample frequency by `ratio`, e.g. 1.001 yields 0.1% more
+    /// samples, to keep an audio buffer in sync with a clock of its own such
+    /// as the video refresh. The ratio applies on top of the configured
+    /// frequencies of any sampling method and is kept across parameter
+    /// changes. It takes effect with the next sample, the resampling
+    /// filters are not recomputed. Valid range is [0.9, 1.1].
+    pub fn set_rate_ratio(&mut self, ratio: f64) -> Result<(), Error> {
+        if !(0.9..=1.1).contains(&ratio) {
+            return Err(Error::InvalidRateRatio);
+        }
+        self.rate_ratio = ratio;
+        self.cycles_per_sample = self.adjusted_cycles_per_sample();
+        // A shorter sample period must not start before the current cycle.
+        self.offset = self.offset.max(-(self.cycles_per_sample as i32));
+        Ok(())
+    }
+
+    fn adjusted_cycles_per_sample(&self) -> u32 {
+        let cycles_per_sample = self.nominal_cycles_per_sample as f64 / self.rate_ratio + 0.5;
+        let max = (MAX_CYCLES_PER_SAMPLE as u32) << FIXP_SHIFT;
+        (cycles_per_sample as u32).clamp(1 << FIXP_SHIFT, max - 1)
+    }
+
+    /// Convolution kernel in use.
+    pub fn simd(&self) -> Simd {
+        self.simd
+    }
+
+    /// Overrides the convolution kernel selected by `Simd::detect`.
+    pub fn set_simd(&mut self, simd: Simd) -> Result<(), Error> {
+        if !simd.is_supported() {
+            return Err(Error::UnsupportedSimd);
+        }
+        self.simd = simd;
+        Ok(())
+    }
+
     #[inline]
     pub fn compute_convolution_fir(&self, sample: &[i16], fir: &[i16]) -> i32 {
-        #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
-        {
-            if self.use_avx2 {
-                return unsafe { self.compute_convolution_fir_avx2(sample, fir) };
-            }
-            if self.use_sse42 {
-                return unsafe { self.compute_convolution_fir_sse(sample, fir) };
-            }
+        // The kernel is only ever set to one supported by the CPU.
+        match self.simd {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Simd::Avx2 => unsafe { self.compute_convolution_fir_avx2(sample, fir) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Simd::Sse42 => unsafe { self.compute_convolution_fir_sse(sample, fir) },
+            #[cfg(target_arch = "aarch64")]
+            Simd::Neon => unsafe { self.compute_convolution_fir_neon(sample, fir) },
+            #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+            Simd::Simd128 => self.compute_convolution_fir_simd128(sample, fir),
+            _ => self.compute_convolution_fir_fallback(sample, fir),
         }
-        self.compute_convolution_fir_fallback(sample, fir)
     }
 
     #[target_feature(enable = "avx2")]
-    #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub unsafe fn compute_convolution_fir_avx2(&self, sample: &[i16], fir: &[i16]) -> i32 {
         #[cfg(target_arch = "x86")]
-        use alloc::arch::x86::*;
+        use core::arch::x86::*;
         #[cfg(target_arch = "x86_64")]
-        use alloc::arch::x86_64::*;
+        use core::arch::x86_64::*;
 
         // Convolution with filter impulse response.
-        let len = alloc::cmp::min(sample.len(), fir.len());
+        let len = core::cmp::min(sample.len(), fir.len());
         let mut fs = &fir[..len];
         let mut ss = &sample[..len];
         let mut v1 = _mm256_set1_epi32(0);
@@ -454,15 +1686,15 @@ impl Sampler {
     }
 
     #[target_feature(enable = "sse4.2")]
-    #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub unsafe fn compute_convolution_fir_sse(&self, sample: &[i16], fir: &[i16]) -> i32 {
         #[cfg(target_arch = "x86")]
-        use alloc::arch::x86::*;
+        use core::arch::x86::*;
         #[cfg(target_arch = "x86_64")]
-        use alloc::arch::x86_64::*;
+        use core::arch::x86_64::*;
 
         // Convolution with filter impulse response.
-        let len = alloc::cmp::min(sample.len(), fir.len());
+        let len = core::cmp::min(sample.len(), fir.len());
         let mut fs = &fir[..len];
         let mut ss = &sample[..len];
         let mut v1 = _mm_set1_epi32(0);
@@ -501,6 +1733,87 @@ impl Sampler {
         v
     }
 
+    #[target_feature(enable = "neon")]
+    #[cfg(target_arch = "aarch64")]
+    pub unsafe fn compute_convolution_fir_neon(&self, sample: &[i16], fir: &[i16]) -> i32 {
+        use core::arch::aarch64::*;
+
+        // Convolution with filter impulse response.
+        let len = core::cmp::min(sample.len(), fir.len());
+        let mut fs = &fir[..len];
+        let mut ss = &sample[..len];
+        let mut v1 = vdupq_n_s32(0);
+        let mut v2 = vdupq_n_s32(0);
+        let mut v3 = vdupq_n_s32(0);
+        let mut v4 = vdupq_n_s32(0);
+        while fs.len() >= 16 {
+            let sv1 = vld1q_s16(ss.as_ptr());
+            let sv2 = vld1q_s16((&ss[8..]).as_ptr());
+            let fv1 = vld1q_s16(fs.as_ptr());
+            let fv2 = vld1q_s16((&fs[8..]).as_ptr());
+            v1 = vmlal_s16(v1, vget_low_s16(sv1), vget_low_s16(fv1));
+            v2 = vmlal_high_s16(v2, sv1, fv1);
+            v3 = vmlal_s16(v3, vget_low_s16(sv2), vget_low_s16(fv2));
+            v4 = vmlal_high_s16(v4, sv2, fv2);
+            fs = &fs[16..];
+            ss = &ss[16..];
+        }
+        v1 = vaddq_s32(v1, v2);
+        v3 = vaddq_s32(v3, v4);
+        v1 = vaddq_s32(v1, v3);
+        let mut v = vaddvq_s32(v1);
+        for i in 0..fs.len() {
+            v += ss[i] as i32 * fs[i] as i32;
+        }
+        v
+    }
+
+    #[target_feature(enable = "simd128")]
+    #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+    pub fn compute_convolution_fir_simd128(&self, sample: &[i16], fir: &[i16]) -> i32 {
+        use core::arch::wasm32::*;
+
+        // Convolution with filter impulse response.
+        let len = core::cmp::min(sample.len(), fir.len());
+        let mut fs = &fir[..len];
+        let mut ss = &sample[..len];
+        let mut v1 = i32x4_splat(0);
+        let mut v2 = i32x4_splat(0);
+        let mut v3 = i32x4_splat(0);
+        let mut v4 = i32x4_splat(0);
+        while fs.len() >= 32 {
+            let (sv1, sv2, sv3, sv4, fv1, fv2, fv3, fv4) = unsafe {
+                (
+                    v128_load(ss.as_ptr() as *const _),
+                    v128_load((&ss[8..]).as_ptr() as *const _),
+                    v128_load((&ss[16..]).as_ptr() as *const _),
+                    v128_load((&ss[24..]).as_ptr() as *const _),
+                    v128_load(fs.as_ptr() as *const _),
+                    v128_load((&fs[8..]).as_ptr() as *const _),
+                    v128_load((&fs[16..]).as_ptr() as *const _),
+                    v128_load((&fs[24..]).as_ptr() as *const _),
+                )
+            };
+            v1 = i32x4_add(v1, i32x4_dot_i16x8(sv1, fv1));
+            v2 = i32x4_add(v2, i32x4_dot_i16x8(sv2, fv2));
+            v3 = i32x4_add(v3, i32x4_dot_i16x8(sv3, fv3));
+            v4 = i32x4_add(v4, i32x4_dot_i16x8(sv4, fv4));
+            fs = &fs[32..];
+            ss = &ss[32..];
+        }
+        v1 = i32x4_add(v1, v2);
+        v3 = i32x4_add(v3, v4);
+        v1 = i32x4_add(v1, v3);
+        let mut v = i32x4_extract_lane::<0>(v1)
+            + i32x4_extract_lane::<1>(v1)
+            + i32x4_extract_lane::<2>(v1)
+            + i32x4_extract_lane::<3>(v1);
+        for i in 0..fs.len() {
+            v += ss[i] as i32 * fs[i] as i32;
+        }
+        v
+    }
+
     #[inline]
     pub fn compute_convolution_fir_fallback(&self, sample: &[i16], fir: &[i16]) -> i32 {
         if sample.len() < fir.len() {
@@ -534,119 +1847,453 @@ impl Sampler {
     fn update_sample_offset2(&mut self, next_sample_offset: i32) {
         self.offset = next_sample_offset & FIXP_MASK;
     }
+}
 
-    #[cfg(feature = "alloc")]
-    fn init_fir(
-        &mut self,
-        clock_freq: f64,
-        sample_freq: f64,
-        mut pass_freq: f64,
-        filter_scale: f64,
-    ) {
-        let pi = core::f64::consts::PI;
-        let samples_per_cycle = sample_freq / clock_freq;
-        let cycles_per_sample = clock_freq / sample_freq;
-
-        // The default passband limit is 0.9*sample_freq/2 for sample
-        // frequencies below ~ 44.1kHz, and 20kHz for higher sample frequencies.
-        if pass_freq < 0.0 {
-            pass_freq = 20000.0;
-            if 2.0 * pass_freq / sample_freq >= 0.9 {
-                pass_freq = 0.9 * sample_freq / 2.0;
-            }
-        }
+/// Kaiser windowed sinc filter converting from `in_freq` to `out_freq`.
+struct FirDesign {
+    in_freq: f64,
+    out_freq: f64,
+    wc: f64,
+    dw: f64,
+    beta: f64,
+    filter_scale: f64,
+    n: i32,
+    res: i32,
+}
 
-        // 16 bits -> -96dB stopband attenuation.
-        let atten = -20.0f64 * (1.0 / (1i32 << 16) as f64).log10();
-        // A fraction of the bandwidth is allocated to the transition band,
-        let dw = (1.0f64 - 2.0 * pass_freq / sample_freq) * pi;
-        // The cutoff frequency is midway through the transition band.
-        let wc = (2.0f64 * pass_freq / sample_freq + 1.0) * pi / 2.0;
+impl FirDesign {
+    /// Designs a filter with cutoff frequency `wc` and transition bandwidth
+    /// `dw` in radians per output sample, and a table resolution of at least
+    /// `res` per input sample.
+    fn new(
+        in_freq: f64,
+        out_freq: f64,
+        wc: f64,
+        dw: f64,
+        atten: f64,
+        filter_scale: f64,
+        res: i32,
+    ) -> Result<Self, Error> {
+        let cycles_per_sample = in_freq / out_freq;
 
-        // For calculation of beta and N see the reference for the kaiserord
-        // function in the MATLAB Signal Processing Toolbox:
-        // http://www.mathworks.com/access/helpdesk/help/toolbox/signal/kaiserord.html
-        let beta = 0.1102f64 * (atten - 8.7);
-        let io_beta = self.i0(beta);
+        let beta = kaiser_beta(atten);
 
         // The filter order will maximally be 124 with the current constraints.
         // N >= (96.33 - 7.95)/(2.285*0.1*pi) -> N >= 123
         // The filter order is equal to the number of zero crossings, i.e.
         // it should be an even number (sinc is symmetric about x = 0).
-        let mut n_cap = ((atten - 7.95) / (2.285 * dw) + 0.5) as i32;
+        // The length is computed in 64 bits, so that it cannot overflow
+        // before it is checked.
+        let mut n_cap = ((atten - 7.95) / (2.285 * dw) + 0.5) as i64;
         n_cap += n_cap & 1;
 
         // The filter length is equal to the filter order + 1.
         // The filter length must be an odd number (sinc is symmetric about x = 0).
-        self.fir.n = (n_cap as f64 * cycles_per_sample) as i32 + 1;
-        self.fir.n |= 1;
+        let fir_n = ((n_cap as f64 * cycles_per_sample) as i64 + 1) | 1;
+
+        // Check whether the sample ring buffer would overfill.
+        if fir_n >= RING_SIZE as i64 {
+            return Err(Error::UnsupportedSamplingRatio);
+        }
+
+        // The table resolution is rounded up to 2^n, ceil is not available
+        // in core.
+        let log2_res = (res as f64 / cycles_per_sample).ln() / (2.0f64).ln();
+        let mut n = log2_res as i32;
+        if (n as f64) < log2_res {
+            n += 1;
+        }
+
+        Ok(FirDesign {
+            in_freq,
+            out_freq,
+            wc,
+            dw,
+            beta,
+            filter_scale,
+            n: fir_n as i32,
+            res: 1 << n.max(0),
+        })
+    }
+
+    /// Designs the filter of a single step resampling method.
+    fn resample(
+        method: SamplingMethod,
+        clock_freq: f64,
+        sample_freq: f64,
+        params: ResampleParams,
+    ) -> Result<Self, Error> {
+        let pi = core::f64::consts::PI;
+        let pass_freq = check_resample_params(sample_freq, params)?;
+
+        // A fraction of the bandwidth is allocated to the transition band,
+        let dw = (1.0f64 - 2.0 * pass_freq / sample_freq) * pi;
+        // The cutoff frequency is midway through the transition band.
+        let wc = (2.0f64 * pass_freq / sample_freq + 1.0) * pi / 2.0;
 
         // We clamp the filter table resolution to 2^n, making the fixpoint
         // sample_offset a whole multiple of the filter table resolution.
-        let res = if self.sampling_method == SamplingMethod::Resample {
+        let res = if method == SamplingMethod::Resample {
             FIR_RES_INTERPOLATE
         } else {
             FIR_RES_FAST
         };
-        let n = ((res as f64 / cycles_per_sample).ln() / (2.0f64).ln()).ceil() as i32;
-        self.fir.res = 1 << n;
+        let mut design = FirDesign::new(
+            clock_freq,
+            sample_freq,
+            wc,
+            dw,
+            params.attenuation,
+            params.filter_scale,
+            res,
+        )?;
+        if let Some(max_latency) = params.max_latency {
+            design.limit_latency(max_latency.min(i32::MAX as u32) as i32)?;
+        }
+        Ok(design)
+    }
+
+    /// Latency of the filter in input samples.
+    fn latency(&self) -> i32 {
+        self.n / 2
+    }
+
+    /// Shortens the filter to a latency of at most `latency` input samples.
+    /// The stopband attenuation achievable with the shorter filter follows
+    /// from the kaiserord formula for N, solved for the attenuation. The
+    /// filter must at least span the main lobe of the sinc function.
+    fn limit_latency(&mut self, latency: i32) -> Result<(), Error> {
+        if self.latency() <= latency {
+            return Ok(());
+        }
+        let order = (latency * 2) as f64 * self.out_freq / self.in_freq;
+        if order < FIR_ORDER_MIN {
+            return Err(Error::LatencyTooLow);
+        }
+        self.n = latency * 2 + 1;
+        self.beta = kaiser_beta(2.285 * self.dw * order + 7.95);
+        Ok(())
+    }
+
+    /// Number of filter table entries.
+    fn len(&self) -> usize {
+        (self.n * self.res) as usize
+    }
 
-        self.fir.data.clear();
-        self.fir
-            .data
-            .resize((self.fir.n * self.fir.res) as usize, 0);
+    /// Calculates the filter table into `data`, which holds `len` entries.
+    fn fill(&self, data: &mut [i16]) {
+        let pi = core::f64::consts::PI;
+        let samples_per_cycle = self.out_freq / self.in_freq;
+        let cycles_per_sample = self.in_freq / self.out_freq;
+        let io_beta = i0(self.beta);
 
         // Calculate fir_RES FIR tables for linear interpolation.
-        for i in 0..self.fir.res {
-            let fir_offset = i * self.fir.n + self.fir.n / 2;
-            let j_offset = i as f64 / self.fir.res as f64;
+        for i in 0..self.res {
+            let fir_offset = i * self.n + self.n / 2;
+            let j_offset = i as f64 / self.res as f64;
             // Calculate FIR table. This is the sinc function, weighted by the
             // Kaiser window.
-            let fir_n_div2 = self.fir.n / 2;
+            let fir_n_div2 = self.n / 2;
             for j in -fir_n_div2..=fir_n_div2 {
                 let jx = j as f64 - j_offset;
-                let wt = wc * jx / cycles_per_sample;
+                let wt = self.wc * jx / cycles_per_sample;
                 let temp = jx / fir_n_div2 as f64;
                 let kaiser = if temp.abs() <= 1.0 {
-                    self.i0(beta * self.sqrt(1.0 - temp * temp)) / io_beta
+                    i0(self.beta * sqrt(1.0 - temp * temp)) / io_beta
                 } else {
                     0f64
                 };
                 let sincwt = if wt.abs() >= 1e-6 { wt.sin() / wt } else { 1.0 };
-                let val = (1i32 << FIR_SHIFT) as f64 * filter_scale * samples_per_cycle * wc / pi
-                    * sincwt
-                    * kaiser;
-                self.fir.data[(fir_offset + j) as usize] = (val + 0.5) as i16;
+                let val =
+                    (1i32 << FIR_SHIFT) as f64 * self.filter_scale * samples_per_cycle * self.wc
+                        / pi
+                        * sincwt
+                        * kaiser;
+                data[(fir_offset + j) as usize] = (val + 0.5) as i16;
             }
         }
     }
+}
 
-    fn i0(&self, x: f64) -> f64 {
-        // Max error acceptable in I0.
-        let i0e = 1e-6;
-        let halfx = x / 2.0;
-        let mut sum = 1.0;
-        let mut u = 1.0;
-        let mut n = 1;
-        loop {
-            let temp = halfx / n as f64;
-            n += 1;
-            u *= temp * temp;
-            sum += u;
-            if u < i0e * sum {
-                break;
+/// Filters of both steps of two-pass resampling.
+struct TwoPassDesign {
+    /// Clock cycles per intermediate sample, 16.16 fixpoint.
+    cycles_per_sample: u32,
+    /// Intermediate samples per output sample, 32.32 fixpoint.
+    samples_per_output: u64,
+    fir1: FirDesign,
+    fir2: FirDesign,
+}
+
+impl TwoPassDesign {
+    fn new(clock_freq: f64, sample_freq: f64, params: ResampleParams) -> Result<Self, Error> {
+        let pi = core::f64::consts::PI;
+        let pass_freq = check_resample_params(sample_freq, params)?;
+
+        // Laurent Ganier's optimal intermediate frequency, rounded to a whole
+        // number of 16.16 fixpoint cycles per sample.
+        let intermediate_freq = 2.0 * pass_freq
+            + sqrt(2.0 * pass_freq * clock_freq * (sample_freq - 2.0 * pass_freq) / sample_freq);
+        let intermediate_freq = intermediate_freq.max(sample_freq).min(clock_freq);
+        let cycles_per_sample =
+            (clock_freq / intermediate_freq * (1 << FIXP_SHIFT) as f64 + 0.5) as u32;
+        let intermediate_freq = clock_freq * (1 << FIXP_SHIFT) as f64 / cycles_per_sample as f64;
+        let samples_per_output =
+            ((intermediate_freq / sample_freq * (1u64 << 32) as f64 + 0.5) as u64).max(1 << 32);
+
+        // Aliasing into the transition band is allowed in both steps, since
+        // it is removed by the second step or lies above the passband. The
+        // transition band thus spans twice the distance from the passband to
+        // nyquist, with the cutoff frequency at nyquist.
+        let mut fir1 = FirDesign::new(
+            clock_freq,
+            intermediate_freq,
+            pi,
+            (1.0f64 - 2.0 * pass_freq / intermediate_freq) * 2.0 * pi,
+            params.attenuation,
+            params.filter_scale,
+            FIR_RES_INTERPOLATE,
+        )?;
+        let mut fir2 = FirDesign::new(
+            intermediate_freq,
+            sample_freq,
+            pi,
+            (1.0f64 - 2.0 * pass_freq / sample_freq) * 2.0 * pi,
+            params.attenuation,
+            1.0,
+            FIR_RES_INTERPOLATE,
+        )?;
+
+        // Both steps are shortened by the same factor to fit the latency.
+        let fir2_latency =
+            fir2.latency() as f64 * cycles_per_sample as f64 / (1 << FIXP_SHIFT) as f64;
+        let latency = fir1.latency() as f64 + fir2_latency;
+        if let Some(max_latency) = params.max_latency {
+            if latency > max_latency as f64 {
+                let scale = max_latency as f64 / latency;
+                fir1.limit_latency((fir1.latency() as f64 * scale) as i32)?;
+                fir2.limit_latency((fir2.latency() as f64 * scale) as i32)?;
             }
         }
-        sum
+        Ok(TwoPassDesign {
+            cycles_per_sample,
+            samples_per_output,
+            fir1,
+            fir2,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.fir1.len() + self.fir2.len()
+    }
+}
+
+/// Number of filter table entries a sampling method needs, i.e. the minimum
+/// inline storage of a sampler for `Sampler::set_parameters_inline`. Zero
+/// for methods which do not resample.
+pub fn fir_table_len(
+    method: SamplingMethod,
+    clock_freq: u32,
+    sample_freq: u32,
+    params: ResampleParams,
+) -> Result<usize, Error> {
+    check_sampling_ratio(clock_freq, sample_freq)?;
+    let (clock_freq, sample_freq) = (clock_freq as f64, sample_freq as f64);
+    match method {
+        SamplingMethod::Resample | SamplingMethod::ResampleFast => {
+            Ok(FirDesign::resample(method, clock_freq, sample_freq, params)?.len())
+        }
+        SamplingMethod::ResampleTwoPass => {
+            Ok(TwoPassDesign::new(clock_freq, sample_freq, params)?.len())
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Kaiser window shape parameter for a stopband attenuation in dB. For
+/// calculation of beta and N see the reference for the kaiserord function in
+/// the MATLAB Signal Processing Toolbox:
+/// http://www.mathworks.com/access/helpdesk/help/toolbox/signal/kaiserord.html
+fn kaiser_beta(atten: f64) -> f64 {
+    if atten > 50.0 {
+        0.1102f64 * (atten - 8.7)
+    } else if atten > 21.0 {
+        0.5842f64 * (atten - 21.0).powf(0.4) + 0.07886 * (atten - 21.0)
+    } else {
+        0.0
+    }
+}
+
+/// Checks the sampling frequencies, returning the clock cycles per sample.
+fn check_sampling_ratio(clock_freq: u32, sample_freq: u32) -> Result<f64, Error> {
+    if clock_freq == 0 || sample_freq == 0 {
+        return Err(Error::InvalidFrequency);
+    }
+    // Sample offsets are 16.16 fixpoint, a sample must span at least one
+    // cycle and the offset arithmetics must not overflow.
+    let cycles_per_sample = clock_freq as f64 / sample_freq as f64;
+    if !(1.0..MAX_CYCLES_PER_SAMPLE).contains(&cycles_per_sample) {
+        return Err(Error::UnsupportedSamplingRatio);
+    }
+    Ok(cycles_per_sample)
+}
+
+/// Checks the resampling parameters, returning the passband limit.
+fn check_resample_params(sample_freq: f64, params: ResampleParams) -> Result<f64, Error> {
+    // The default passband limit is 0.9*sample_freq/2 for sample
+    // frequencies below ~ 44.1kHz, and 20kHz for higher sample frequencies.
+    let mut pass_freq = params.pass_freq;
+    if pass_freq < 0.0 {
+        pass_freq = 20000.0;
+        if 2.0 * pass_freq / sample_freq >= 0.9 {
+            pass_freq = 0.9 * sample_freq / 2.0;
+        }
+    }
+    // Check whether the FIR table would overfill.
+    else if pass_freq.is_nan() || pass_freq > 0.9 * sample_freq / 2.0 {
+        return Err(Error::PassbandTooWide);
+    } else if pass_freq == 0.0 {
+        return Err(Error::PassbandEmpty);
+    }
+    // The filter scaling is only included to avoid clipping, so keep it sane.
+    if !(0.9..=1.0).contains(&params.filter_scale) {
+        return Err(Error::InvalidFilterScale);
+    }
+    if !(ATTENUATION_MIN..=ATTENUATION_MAX).contains(&params.attenuation) {
+        return Err(Error::InvalidAttenuation);
+    }
+    Ok(pass_freq)
+}
+
+/// Computes the filter table of a design into `storage` at `offset` or on
+/// the heap. Without a heap the table must be stored inline.
+fn build_fir(design: &FirDesign, storage: Option<&mut [i16]>, offset: usize) -> Result<Fir, Error> {
+    let data = match storage {
+        Some(storage) => {
+            design.fill(&mut storage[offset..offset + design.len()]);
+            FirData::Inline(offset)
+        }
+        #[cfg(feature = "alloc")]
+        None => {
+            let mut data = alloc::vec![0; design.len()];
+            design.fill(&mut data);
+            FirData::Shared(Arc::from(data))
+        }
+        #[cfg(not(feature = "alloc"))]
+        None => return Err(Error::BufferTooSmall),
+    };
+    Ok(Fir {
+        data,
+        n: design.n,
+        res: design.res,
+    })
+}
+
+/// Checks that the inline storage can hold filter tables of `len` entries.
+/// Without a heap the tables must be stored inline.
+fn check_fir_storage(storage: Option<&[i16]>, len: usize) -> Result<(), Error> {
+    match storage {
+        Some(storage) if storage.len() < len => Err(Error::BufferTooSmall),
+        None if !cfg!(feature = "alloc") => Err(Error::BufferTooSmall),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that a ring of `capacity` samples can hold the history of a filter.
+fn check_ring_capacity(design: &FirDesign, capacity: usize) -> Result<(), Error> {
+    if ring_size(design.n, 0) <= capacity {
+        Ok(())
+    } else {
+        Err(Error::BufferTooSmall)
+    }
+}
+
+/// Ring size of a filter of order `n` and additional delay, holding one
+/// more sample of history for the interpolation between adjacent filter
+/// tables.
+fn ring_size(n: i32, delay: usize) -> usize {
+    if n == 0 {
+        0
+    } else {
+        (n as usize + delay + 1).next_power_of_two()
+    }
+}
+
+fn i0(x: f64) -> f64 {
+    // Max error acceptable in I0.
+    let i0e = 1e-6;
+    let halfx = x / 2.0;
+    let mut sum = 1.0;
+    let mut u = 1.0;
+    let mut n = 1;
+    loop {
+        let temp = halfx / n as f64;
+        n += 1;
+        u *= temp * temp;
+        sum += u;
+        if u < i0e * sum {
+            break;
+        }
     }
+    sum
+}
+
+#[cfg(feature = "std")]
+fn sqrt(value: f64) -> f64 {
+    value.sqrt()
+}
+
+#[cfg(not(feature = "std"))]
+fn sqrt(value: f64) -> f64 {
+    math::sqrt(value)
+}
+
+/// Checks whether a frame of `C` channels still fits into the output buffer.
+#[inline]
+fn has_room<const C: usize>(index: usize, len: usize, interleave: usize) -> bool {
+    index * interleave + C <= len
+}
 
-    #[cfg(feature = "std")]
-    fn sqrt(&self, value: f64) -> f64 {
-        value.sqrt()
+/// Resamples a history in chronological order into `ring`, aligning the
+/// most recent samples, to a sample spacing of `ratio` times the current one
+/// by linear interpolation. Samples older than the history are silent.
+fn resample_history(history: &[i16], ring: &mut [i16], ratio: f64) {
+    let size = ring.len();
+    for sample in ring.iter_mut() {
+        *sample = 0;
     }
+    // Walk back from the most recent sample.
+    for age in 0..size {
+        let position = age as f64 * ratio;
+        let k = position as usize;
+        if k + 1 >= history.len() {
+            break;
+        }
+        let newer = history[history.len() - 1 - k] as f64;
+        let older = history[history.len() - 2 - k] as f64;
+        ring[size - 1 - age] = (newer + (older - newer) * (position - k as f64) + 0.5) as i16;
+    }
+}
+
+/// Rescales ring buffer samples from one headroom to another.
+fn rescale(ring: &mut [i16], from_shift: i32, to_shift: i32) {
+    for sample in ring.iter_mut() {
+        let value = if to_shift > from_shift {
+            (*sample as i32) >> (to_shift - from_shift)
+        } else {
+            (*sample as i32) << (from_shift - to_shift)
+        };
+        *sample = saturate(value);
+    }
+}
 
-    #[cfg(not(feature = "std"))]
-    fn sqrt(&self, value: f64) -> f64 {
-        math::sqrt(value)
+#[inline]
+pub(crate) fn saturate(sample: i32) -> i16 {
+    if sample >= OUTPUT_HALF {
+        (OUTPUT_HALF - 1) as i16
+    } else if sample < -OUTPUT_HALF {
+        (-OUTPUT_HALF) as i16
+    } else {
+        sample as i16
     }
 }
@@ -3,11 +3,24 @@
 // Portions (c) 2004 Dag Lem <resid@nimrod.no>
 // Licensed under the GPLv3. See LICENSE file in the project root for full license text.
 
-use super::envelope::State as EnvState;
-use super::sampler::{Sampler, SamplingMethod};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use super::envelope::{self, State as EnvState};
+use super::error::{check, Error};
+use super::queue::{Scheduled, WriteQueue};
+#[cfg(feature = "alloc")]
+use super::queue::{QUEUE_SIZE, REG_MAX};
+#[cfg(feature = "alloc")]
+use super::sampler::FirTable;
+use super::sampler::{History, RING_SIZE, TWO_PASS_RING_SIZE};
+use super::sampler::{OutputStats, ResampleParams, Sample, Sampler, SamplingMethod, Simd};
 use super::synth::Synth;
+use super::wave::SHIFT_RESET_8580;
 use super::ChipModel;
 
+pub(crate) const BUS_VALUE_TTL: u32 = 0x2000;
+
 pub mod reg {
     pub const FREQLO1: u8 = 0x00;
     pub const FREQHI1: u8 = 0x01;
@@ -40,8 +53,12 @@ pub mod reg {
     pub const ENV3: u8 = 0x1c;
 }
 
-#[derive(Debug)]
-pub struct State {
+/// Snapshot of the runtime state of a single chip: its registers, data bus,
+/// voices, filters and pending writes. The pending writes are only kept
+/// with the `alloc` feature.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChipState {
     // Sid
     pub sid_register: [u8; 32],
     pub bus_value: u8,
@@ -50,6 +67,12 @@ pub struct State {
     // Wave
     pub accumulator: [u32; 3],
     pub shift_register: [u32; 3],
+    pub msb_rising: [u8; 3],
+    pub shift_pipeline: [u8; 3],
+    pub pulse_output: [u16; 3],
+    pub shift_register_reset: [u32; 3],
+    pub floating_output: [u16; 3],
+    pub floating_output_ttl: [u32; 3],
     // Envelope
     pub envelope_state: [u8; 3],
     pub envelope_counter: [u8; 3],
@@ -58,62 +81,429 @@ pub struct State {
     pub hold_zero: [u8; 3],
     pub rate_counter: [u16; 3],
     pub rate_counter_period: [u16; 3],
+    // Filter
+    pub filter_vhp: i32,
+    pub filter_vbp: i32,
+    pub filter_vlp: i32,
+    pub filter_vnf: i32,
+    // External Filter
+    pub ext_filter_vlp: i32,
+    pub ext_filter_vhp: i32,
+    pub ext_filter_vo: i32,
+    // Write Queue
+    #[cfg(feature = "alloc")]
+    pub pending_writes: Vec<(u32, u8, u8)>,
+}
+
+/// The state of a chip after reset.
+impl Default for ChipState {
+    fn default() -> Self {
+        // The paddles read as unconnected.
+        let mut sid_register = [0; 32];
+        sid_register[reg::POTX as usize] = 0xff;
+        sid_register[reg::POTY as usize] = 0xff;
+        ChipState {
+            sid_register,
+            bus_value: 0,
+            bus_value_ttl: 0,
+            ext_in: 0,
+            accumulator: [0; 3],
+            shift_register: [0x007f_fff8; 3],
+            msb_rising: [0; 3],
+            shift_pipeline: [0; 3],
+            pulse_output: [0; 3],
+            shift_register_reset: [0; 3],
+            floating_output: [0; 3],
+            floating_output_ttl: [0; 3],
+            envelope_state: [EnvState::Release as u8; 3],
+            envelope_counter: [0; 3],
+            exponential_counter: [0; 3],
+            exponential_counter_period: [1; 3],
+            hold_zero: [1; 3],
+            rate_counter: [0; 3],
+            rate_counter_period: [envelope::RATE_COUNTER_PERIOD[0]; 3],
+            filter_vhp: 0,
+            filter_vbp: 0,
+            filter_vlp: 0,
+            filter_vnf: 0,
+            ext_filter_vlp: 0,
+            ext_filter_vhp: 0,
+            ext_filter_vo: 0,
+            #[cfg(feature = "alloc")]
+            pending_writes: Vec::new(),
+        }
+    }
 }
 
+impl ChipState {
+    /// Checks that every field holds a value the emulator can reach, so
+    /// restoring the state cannot panic or corrupt emulation.
+    pub fn validate(&self) -> Result<(), Error> {
+        check(self.bus_value_ttl <= BUS_VALUE_TTL, "bus_value_ttl")?;
+        for i in 0..3 {
+            check(self.accumulator[i] <= 0x00ff_ffff, "accumulator")?;
+            check(self.shift_register[i] <= 0x007f_ffff, "shift_register")?;
+            check(self.msb_rising[i] <= 1, "msb_rising")?;
+            check(self.shift_pipeline[i] <= 2, "shift_pipeline")?;
+            check(
+                self.pulse_output[i] == 0 || self.pulse_output[i] == 0x0fff,
+                "pulse_output",
+            )?;
+            check(
+                self.shift_register_reset[i] <= SHIFT_RESET_8580,
+                "shift_register_reset",
+            )?;
+            check(self.floating_output[i] <= 0x0fff, "floating_output")?;
+            check(self.envelope_state[i] <= 2, "envelope_state")?;
+            check(
+                [1, 2, 4, 8, 16, 30].contains(&self.exponential_counter_period[i]),
+                "exponential_counter_period",
+            )?;
+            check(
+                self.exponential_counter[i] < self.exponential_counter_period[i],
+                "exponential_counter",
+            )?;
+            check(self.hold_zero[i] <= 1, "hold_zero")?;
+            check(self.rate_counter[i] <= 0x7fff, "rate_counter")?;
+            check(
+                envelope::is_rate_counter_period(self.rate_counter_period[i]),
+                "rate_counter_period",
+            )?;
+        }
+        #[cfg(feature = "alloc")]
+        {
+            check(self.pending_writes.len() <= QUEUE_SIZE, "pending_writes")?;
+            let mut last = 0;
+            for &(delta, reg, _) in self.pending_writes.iter() {
+                check(delta >= last && reg <= REG_MAX, "pending_writes")?;
+                last = delta;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the registers and internal state of a chip.
+    pub(crate) fn read(
+        synth: &Synth,
+        bus_value: u8,
+        bus_value_ttl: u32,
+        queue: &WriteQueue,
+    ) -> Self {
+        let mut state = ChipState {
+            bus_value,
+            bus_value_ttl,
+            ext_in: synth.ext_in,
+            #[cfg(feature = "alloc")]
+            pending_writes: queue.pending().collect(),
+            ..ChipState::default()
+        };
+        #[cfg(not(feature = "alloc"))]
+        let _ = queue;
+        for i in 0..3 {
+            let j = i * 7;
+            let wave = &synth.voices[i].wave;
+            let envelope = &synth.voices[i].envelope;
+            state.sid_register[j] = wave.get_frequency_lo();
+            state.sid_register[j + 1] = wave.get_frequency_hi();
+            state.sid_register[j + 2] = wave.get_pulse_width_lo();
+            state.sid_register[j + 3] = wave.get_pulse_width_hi();
+            state.sid_register[j + 4] = wave.get_control() | envelope.get_control();
+            state.sid_register[j + 5] = envelope.get_attack_decay();
+            state.sid_register[j + 6] = envelope.get_sustain_release();
+        }
+        let filter = &synth.filter;
+        state.sid_register[0x15] = filter.get_fc_lo();
+        state.sid_register[0x16] = filter.get_fc_hi();
+        state.sid_register[0x17] = filter.get_res_filt();
+        state.sid_register[0x18] = filter.get_mode_vol();
+        for i in 0x19..0x1d {
+            state.sid_register[i] = synth.read(i as u8, bus_value);
+        }
+        for i in 0x1d..0x20 {
+            state.sid_register[i] = 0;
+        }
+        for i in 0..3 {
+            let wave = &synth.voices[i].wave;
+            let envelope = &synth.voices[i].envelope;
+            state.accumulator[i] = wave.get_acc();
+            state.shift_register[i] = wave.get_shift();
+            state.msb_rising[i] = if wave.msb_rising { 1 } else { 0 };
+            state.shift_pipeline[i] = wave.shift_pipeline;
+            state.pulse_output[i] = wave.pulse_output;
+            state.shift_register_reset[i] = wave.shift_reset;
+            state.floating_output[i] = wave.floating_output;
+            state.floating_output_ttl[i] = wave.floating_output_ttl;
+            state.envelope_state[i] = envelope.state as u8;
+            state.envelope_counter[i] = envelope.envelope_counter;
+            state.exponential_counter[i] = envelope.exponential_counter;
+            state.exponential_counter_period[i] = envelope.exponential_counter_period;
+            state.hold_zero[i] = if envelope.hold_zero { 1 } else { 0 };
+            state.rate_counter[i] = envelope.rate_counter;
+            state.rate_counter_period[i] = envelope.rate_counter_period;
+        }
+        state.filter_vhp = filter.vhp;
+        state.filter_vbp = filter.vbp;
+        state.filter_vlp = filter.vlp;
+        state.filter_vnf = filter.vnf;
+        let ext_filter = &synth.ext_filter;
+        state.ext_filter_vlp = ext_filter.vlp;
+        state.ext_filter_vhp = ext_filter.vhp;
+        state.ext_filter_vo = ext_filter.vo;
+        state
+    }
+
+    /// Restores the registers and internal state of a chip as read by
+    /// `read`. The state must be validated first. Without the `alloc`
+    /// feature the write queue is left empty.
+    pub(crate) fn write(
+        &self,
+        synth: &mut Synth,
+        bus_value: &mut u8,
+        bus_value_ttl: &mut u32,
+        queue: &mut WriteQueue,
+    ) {
+        for i in 0..0x19 {
+            synth.write(i, self.sid_register[i as usize]);
+        }
+        synth.ext_in = self.ext_in;
+        for i in 0..3 {
+            let envelope = &mut synth.voices[i].envelope;
+            synth.voices[i].wave.acc = self.accumulator[i];
+            synth.voices[i].wave.shift = self.shift_register[i];
+            synth.voices[i].wave.msb_rising = self.msb_rising[i] != 0;
+            synth.voices[i].wave.shift_pipeline = self.shift_pipeline[i];
+            synth.voices[i].wave.pulse_output = self.pulse_output[i];
+            synth.voices[i].wave.shift_reset = self.shift_register_reset[i];
+            synth.voices[i].wave.floating_output = self.floating_output[i];
+            synth.voices[i].wave.floating_output_ttl = self.floating_output_ttl[i];
+            envelope.state = match self.envelope_state[i] {
+                0 => EnvState::Attack,
+                1 => EnvState::DecaySustain,
+                _ => EnvState::Release,
+            };
+            envelope.envelope_counter = self.envelope_counter[i];
+            envelope.exponential_counter = self.exponential_counter[i];
+            envelope.exponential_counter_period = self.exponential_counter_period[i];
+            envelope.hold_zero = self.hold_zero[i] != 0;
+            envelope.rate_counter = self.rate_counter[i];
+            envelope.rate_counter_period = self.rate_counter_period[i];
+        }
+        let filter = &mut synth.filter;
+        filter.vhp = self.filter_vhp;
+        filter.vbp = self.filter_vbp;
+        filter.vlp = self.filter_vlp;
+        filter.vnf = self.filter_vnf;
+        let ext_filter = &mut synth.ext_filter;
+        ext_filter.vlp = self.ext_filter_vlp;
+        ext_filter.vhp = self.ext_filter_vhp;
+        ext_filter.vo = self.ext_filter_vo;
+        *bus_value = self.bus_value;
+        *bus_value_ttl = self.bus_value_ttl;
+        queue.reset();
+        #[cfg(feature = "alloc")]
+        for &(delta, reg, value) in self.pending_writes.iter() {
+            queue.push(delta, reg, value);
+        }
+    }
+}
+
+/// Snapshot of the complete emulator runtime state. Sampling parameters
+/// are configuration and must match between `read_state` and `write_state`
+/// for restored output to be bit-exact. The resampling history and pending
+/// writes are only kept with the `alloc` feature, see `Sid::try_read_state`.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct State {
+    pub chip: ChipState,
+    pub cycles_elapsed: u64,
+    // Sampler
+    pub sampler_offset: i32,
+    pub sampler_prev_sample: i32,
+    pub sampler_ring_shift: i32,
+    pub sampler_delay: u32,
+    #[cfg(feature = "alloc")]
+    pub sampler_ring: Vec<i16>,
+    #[cfg(feature = "alloc")]
+    pub sampler_two_pass_offset: u32,
+    #[cfg(feature = "alloc")]
+    pub sampler_two_pass_remaining: u32,
+    #[cfg(feature = "alloc")]
+    pub sampler_two_pass_ring: Vec<i16>,
+}
+
+impl State {
+    /// Checks that every field holds a value the emulator can reach, so
+    /// restoring the state cannot panic or corrupt emulation.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.chip.validate()?;
+        self.history().validate()
+    }
+
+    fn history(&self) -> History<'_, 1> {
+        History {
+            offset: self.sampler_offset,
+            prev_sample: [self.sampler_prev_sample],
+            ring_shift: self.sampler_ring_shift,
+            delay: self.sampler_delay,
+            #[cfg(feature = "alloc")]
+            ring: &self.sampler_ring,
+            #[cfg(feature = "alloc")]
+            two_pass: (
+                self.sampler_two_pass_offset,
+                self.sampler_two_pass_remaining,
+                &self.sampler_two_pass_ring,
+            ),
+            // Without a heap the state holds no history, restore silence.
+            #[cfg(not(feature = "alloc"))]
+            ring: &[],
+            #[cfg(not(feature = "alloc"))]
+            two_pass: (0, 0, &[]),
+        }
+    }
+}
+
+/// Ages the value left on the data bus by the last write, which reads
+/// back as zero once its time to live has passed.
+pub(crate) fn age_bus_value(bus_value: &mut u8, bus_value_ttl: &mut u32, delta: u32) {
+    *bus_value_ttl = bus_value_ttl.saturating_sub(delta);
+    if *bus_value_ttl == 0 {
+        *bus_value = 0;
+    }
+}
+
+/// MOS6581/MOS8580 emulation. The sampler keeps a resampling history of up
+/// to `R` samples, `F` filter table entries inline and a two-pass
+/// resampling history of up to `P` samples, see `Sid::with_capacity`.
 #[derive(Clone)]
-pub struct Sid {
+pub struct Sid<const R: usize = RING_SIZE, const F: usize = 0, const P: usize = TWO_PASS_RING_SIZE>
+{
     // Functional Units
-    sampler: Sampler,
+    sampler: Sampler<Synth, 1, R, F, P>,
     // Runtime State
     bus_value: u8,
     bus_value_ttl: u32,
+    cycles_elapsed: u64,
+    queue: WriteQueue,
 }
 
 impl Sid {
     pub fn new(chip_model: ChipModel) -> Self {
+        Sid::with_capacity(chip_model)
+    }
+}
+
+impl<const R: usize, const F: usize, const P: usize> Sid<R, F, P> {
+    /// Creates a SID with a sampler ring capacity of `R` samples, inline
+    /// filter table storage of `F` entries and a two-pass ring capacity of
+    /// `P` samples, see `Sampler::with_capacity`. The default of `RING_SIZE`
+    /// fits any resampling filter, without the `alloc` feature smaller rings
+    /// save memory, e.g. `Sid::<0, 0, 0>` for `Fast` and `Interpolate` only,
+    /// while the resampling methods need storage for their filter tables,
+    /// see `sampler::fir_table_len`.
+    pub fn with_capacity(chip_model: ChipModel) -> Self {
         let synth = Synth::new(chip_model);
         let mut sid = Sid {
-            sampler: Sampler::new(synth),
+            sampler: Sampler::with_capacity(synth),
             bus_value: 0,
             bus_value_ttl: 0,
+            cycles_elapsed: 0,
+            queue: WriteQueue::default(),
         };
-        sid.set_sampling_parameters(SamplingMethod::Fast, 985_248, 44100);
+        sid.set_sampling_parameters(SamplingMethod::Fast, 985_248, 44100)
+            .expect("default sampling parameters");
         sid
     }
 
+    /// Sets the sampling method and frequencies. Returns an error and keeps
+    /// the previous parameters if the combination is not supported, e.g. a
+    /// zero frequency or a clock to sample frequency ratio the resampler
+    /// cannot handle.
     pub fn set_sampling_parameters(
         &mut self,
         method: SamplingMethod,
         clock_freq: u32,
         sample_freq: u32,
-    ) {
-        self.sampler.set_parameters(method, clock_freq, sample_freq);
+    ) -> Result<(), Error> {
+        self.sampler.set_parameters(method, clock_freq, sample_freq)
+    }
+
+    /// Sets the sampling parameters with a custom resampling filter, e.g. a
+    /// wider passband for high sample frequencies or a lower attenuation for
+    /// cheaper output. See `ResampleParams`.
+    pub fn set_sampling_parameters_with(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<(), Error> {
+        self.sampler
+            .set_parameters_with(method, clock_freq, sample_freq, params)
+    }
+
+    /// Sets the sampling parameters with the resampling filter tables stored
+    /// inline rather than on the heap, which makes the resampling methods
+    /// available without the `alloc` feature. The required storage is given
+    /// by `sampler::fir_table_len`.
+    pub fn set_sampling_parameters_inline(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<(), Error> {
+        self.sampler
+            .set_parameters_inline(method, clock_freq, sample_freq, params)
+    }
+
+    /// Changes the sampling parameters while keeping the sample history, so
+    /// that the output continues seamlessly across a change of the sample
+    /// frequency. See `Sampler::change_parameters`.
+    pub fn change_sampling_parameters(
+        &mut self,
+        method: SamplingMethod,
+        clock_freq: u32,
+        sample_freq: u32,
+        params: ResampleParams,
+    ) -> Result<(), Error> {
+        self.sampler
+            .change_parameters(method, clock_freq, sample_freq, params)
+    }
+
+    /// Sets the sampling parameters of a precomputed filter table, which is
+    /// shared with all other chips using it. See `sampler::FirCache` for
+    /// setting up many chips. Returns an error if the ring capacity cannot
+    /// hold the history of the filters.
+    #[cfg(feature = "alloc")]
+    pub fn set_sampling_parameters_shared(&mut self, table: &FirTable) -> Result<(), Error> {
+        self.sampler.set_parameters_shared(table)
+    }
+
+    /// Nudges the sample frequency by a ratio close to 1.0 without
+    /// recomputing the resampling filters, e.g. to keep the audio buffer
+    /// from under- or overrunning. See `Sampler::set_rate_ratio`.
+    pub fn set_rate_ratio(&mut self, ratio: f64) -> Result<(), Error> {
+        self.sampler.set_rate_ratio(ratio)
+    }
+
+    /// Overrides the SIMD kernel used for resampling, which defaults to the
+    /// fastest one supported by the CPU. Returns an error if the CPU does not
+    /// support the kernel.
+    pub fn set_simd(&mut self, simd: Simd) -> Result<(), Error> {
+        self.sampler.set_simd(simd)
     }
 
     pub fn clock(&mut self) {
-        // Age bus value.
-        if self.bus_value_ttl > 0 {
-            self.bus_value_ttl -= 1;
-            if self.bus_value_ttl == 0 {
-                self.bus_value = 0;
-            }
-        }
+        // Apply writes due on this cycle.
+        self.write_pending();
+        age_bus_value(&mut self.bus_value, &mut self.bus_value_ttl, 1);
         // Clock synthesizer.
         self.sampler.synth.clock();
+        self.advance(1);
     }
 
     pub fn clock_delta(&mut self, delta: u32) {
-        // Age bus value.
-        if self.bus_value_ttl >= delta {
-            self.bus_value_ttl -= delta;
-        } else {
-            self.bus_value_ttl = 0;
-        }
-        if self.bus_value_ttl == 0 {
-            self.bus_value = 0;
-        }
-        // Clock synthesizer.
-        self.sampler.synth.clock_delta(delta);
+        self.clock_scheduled(delta);
     }
 
     pub fn enable_external_filter(&mut self, enabled: bool) {
@@ -124,6 +514,66 @@ impl Sid {
         self.sampler.synth.filter.set_enabled(enabled);
     }
 
+    /// Enables modeling of the 2 cycle delay of the noise output and the
+    /// 1 cycle delay of the pulse output, see `WaveformGenerator`. Off by
+    /// default, matching the original reSID 0.16 output.
+    pub fn enable_pipeline_delays(&mut self, enabled: bool) {
+        for voice in self.sampler.synth.voices.iter_mut() {
+            voice.wave.set_pipeline_delays(enabled);
+        }
+    }
+
+    /// Enables the non-linear model of the waveform and envelope DACs. The
+    /// MOS6581 R-2R ladders are not binary weighted, which colours the sound,
+    /// while the MOS8580 ladders are practically linear. Off by default.
+    pub fn enable_dac_model(&mut self, enabled: bool) {
+        for voice in self.sampler.synth.voices.iter_mut() {
+            voice.set_dac_model(enabled);
+        }
+    }
+
+    /// Enables modeling of the time the test bit must be held for the noise
+    /// shift register to reset, 35000 cycles on the MOS6581 and 2519864 on
+    /// the MOS8580, see `WaveformGenerator`. Off by default, matching the
+    /// original reSID 0.16 output.
+    pub fn enable_shift_register_reset(&mut self, enabled: bool) {
+        for voice in self.sampler.synth.voices.iter_mut() {
+            voice.wave.set_shift_reset_delay(enabled);
+        }
+    }
+
+    /// Enables modeling of the waveform output floating at the last level
+    /// after the waveform is cleared, as seen on OSC3 and in the audio
+    /// output, and of its fading afterwards, see `set_floating_output_ttl`
+    /// and `set_floating_output_fade`. Off by default, matching the original
+    /// reSID 0.16 output.
+    pub fn enable_floating_output(&mut self, enabled: bool) {
+        for voice in self.sampler.synth.voices.iter_mut() {
+            voice.wave.set_floating_output(enabled);
+        }
+    }
+
+    /// Sets the number of cycles the floating waveform output keeps the last
+    /// level before it starts to fade out, see `enable_floating_output`.
+    /// Defaults to 54000 for the MOS6581 and 800000 for the MOS8580, 0 drops
+    /// the output to zero at once.
+    pub fn set_floating_output_ttl(&mut self, cycles: u32) {
+        for voice in self.sampler.synth.voices.iter_mut() {
+            voice.wave.set_floating_output_ttl(cycles);
+        }
+    }
+
+    /// Sets the number of cycles between the steps in which the floating
+    /// waveform output fades out once its floating time has passed, each
+    /// step clearing the bits whose next higher bit is cleared. Defaults to
+    /// 1400 for the MOS6581 and 50000 for the MOS8580, 0 drops the output
+    /// to zero at the end of the floating time.
+    pub fn set_floating_output_fade(&mut self, cycles: u32) {
+        for voice in self.sampler.synth.voices.iter_mut() {
+            voice.wave.set_floating_output_fade(cycles);
+        }
+    }
+
     pub fn input(&mut self, sample: i32) {
         // Voice outputs are 20 bits. Scale up to match three voices in order
         // to facilitate simulation of the MOS8580 "digi boost" hardware hack.
@@ -134,10 +584,36 @@ impl Sid {
         self.sampler.synth.output()
     }
 
+    /// Sets the master output gain, 1.0 being the nominal SID level.
+    /// Lower values provide headroom for tunes which would otherwise clip.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.sampler.synth.set_gain(gain);
+    }
+
+    /// Clipped sample count and peak level of the last `sample` call.
+    pub fn output_stats(&self) -> OutputStats {
+        self.sampler.stats()
+    }
+
+    /// Number of cycles clocked since the SID was created. Kept by `reset`,
+    /// which resets the chip but not the timeline.
+    pub fn cycles_elapsed(&self) -> u64 {
+        self.cycles_elapsed
+    }
+
+    /// Delay of the sampled output behind the chip in cycles, i.e. a sample
+    /// produced after `cycles_elapsed` cycles reflects the chip output of
+    /// `output_latency_cycles` cycles earlier. Zero for `Fast` and
+    /// `Interpolate`, half the filter length for the resampling methods.
+    pub fn output_latency_cycles(&self) -> u32 {
+        self.sampler.latency()
+    }
+
     pub fn reset(&mut self) {
         self.sampler.reset();
         self.bus_value = 0;
         self.bus_value_ttl = 0;
+        self.queue.reset();
     }
 
     /// SID clocking with audio sampling.
@@ -156,8 +632,41 @@ impl Sid {
     ///     delta = next_delta;
     /// }
     /// ```
+    ///
+    /// Writes scheduled with `write_at` are applied on their exact cycle
+    /// while the requested cycles are being clocked.
     pub fn sample(&mut self, delta: u32, buffer: &mut [i16], interleave: usize) -> (usize, u32) {
-        self.sampler.clock(delta, buffer, interleave)
+        self.sample_buffer(delta, buffer, interleave)
+    }
+
+    /// SID clocking with floating point audio sampling, see `sample`.
+    ///
+    /// Samples are normalized so that 1.0 corresponds to 16-bit full scale.
+    /// They are not clipped, loud output may exceed [-1.0, 1.0].
+    ///
+    /// The resampling methods buffer the signal in a 16-bit ring with one
+    /// bit of headroom, so there the signal saturates at [-2.0, 2.0) and
+    /// is kept at 15 bits of precision. Such saturation is counted by
+    /// `output_stats`. Switching between `sample` and `sample_f32` rescales
+    /// the buffered history, which drops its lowest bit when going to
+    /// floating point and saturates it when going back to 16-bit.
+    pub fn sample_f32(
+        &mut self,
+        delta: u32,
+        buffer: &mut [f32],
+        interleave: usize,
+    ) -> (usize, u32) {
+        self.sample_buffer(delta, buffer, interleave)
+    }
+
+    fn sample_buffer<T: Sample>(
+        &mut self,
+        delta: u32,
+        buffer: &mut [T],
+        interleave: usize,
+    ) -> (usize, u32) {
+        self.sampler.reset_stats();
+        self.sample_scheduled(delta, buffer, interleave)
     }
 
     // -- Device I/O
@@ -168,93 +677,110 @@ impl Sid {
 
     pub fn write(&mut self, reg: u8, value: u8) {
         self.bus_value = value;
-        self.bus_value_ttl = 0x2000;
+        self.bus_value_ttl = BUS_VALUE_TTL;
         self.sampler.synth.write(reg, value);
     }
 
+    /// Schedules a register write `cycle` cycles from the current SID clock.
+    /// The write is applied by `clock`, `clock_delta` and `sample` right
+    /// before the given cycle is clocked, so callers do not have to split
+    /// clocking at each write to get cycle exact timing.
+    ///
+    /// Writes must be scheduled in cycle order; a write scheduled before an
+    /// already queued one is delayed to the cycle of the latter.
+    /// Returns false if the write queue is full or `reg` is above 0x1f.
+    /// Writes to the read-only registers only drive the data bus.
+    pub fn write_at(&mut self, cycle: u32, reg: u8, value: u8) -> bool {
+        self.queue.push(cycle, reg, value)
+    }
+
     // -- State
 
+    /// Returns the emulator state. Without the `alloc` feature the
+    /// resampling history and pending writes are left out, see
+    /// `try_read_state`.
     pub fn read_state(&self) -> State {
-        let mut state = State {
-            sid_register: [0; 32],
-            bus_value: 0,
-            bus_value_ttl: 0,
-            ext_in: 0,
-            accumulator: [0; 3],
-            shift_register: [0; 3],
-            envelope_state: [0; 3],
-            envelope_counter: [0; 3],
-            exponential_counter: [0; 3],
-            exponential_counter_period: [0; 3],
-            hold_zero: [0; 3],
-            rate_counter: [0; 3],
-            rate_counter_period: [0; 3],
-        };
-        for i in 0..3 {
-            let j = i * 7;
-            let wave = &self.sampler.synth.voices[i].wave;
-            let envelope = &self.sampler.synth.voices[i].envelope;
-            state.sid_register[j] = wave.get_frequency_lo();
-            state.sid_register[j + 1] = wave.get_frequency_hi();
-            state.sid_register[j + 2] = wave.get_pulse_width_lo();
-            state.sid_register[j + 3] = wave.get_pulse_width_hi();
-            state.sid_register[j + 4] = wave.get_control() | envelope.get_control();
-            state.sid_register[j + 5] = envelope.get_attack_decay();
-            state.sid_register[j + 6] = envelope.get_sustain_release();
-        }
-        let filter = &self.sampler.synth.filter;
-        state.sid_register[0x15] = filter.get_fc_lo();
-        state.sid_register[0x16] = filter.get_fc_hi();
-        state.sid_register[0x17] = filter.get_res_filt();
-        state.sid_register[0x18] = filter.get_mode_vol();
-        for i in 0x19..0x1d {
-            state.sid_register[i] = self.read(i as u8);
-        }
-        for i in 0x1d..0x20 {
-            state.sid_register[i] = 0;
-        }
-        state.bus_value = self.bus_value;
-        state.bus_value_ttl = self.bus_value_ttl;
-        state.ext_in = self.sampler.synth.ext_in;
-        for i in 0..3 {
-            let wave = &self.sampler.synth.voices[i].wave;
-            let envelope = &self.sampler.synth.voices[i].envelope;
-            state.accumulator[i] = wave.get_acc();
-            state.shift_register[i] = wave.get_shift();
-            state.envelope_state[i] = envelope.state as u8;
-            state.envelope_counter[i] = envelope.envelope_counter;
-            state.exponential_counter[i] = envelope.exponential_counter;
-            state.exponential_counter_period[i] = envelope.exponential_counter_period;
-            state.hold_zero[i] = if envelope.hold_zero { 1 } else { 0 };
-            state.rate_counter[i] = envelope.rate_counter;
-            state.rate_counter_period[i] = envelope.rate_counter_period;
+        #[cfg(feature = "alloc")]
+        let (two_pass_offset, two_pass_remaining, two_pass_ring) = self.sampler.read_two_pass();
+        State {
+            chip: ChipState::read(
+                &self.sampler.synth,
+                self.bus_value,
+                self.bus_value_ttl,
+                &self.queue,
+            ),
+            cycles_elapsed: self.cycles_elapsed,
+            sampler_offset: self.sampler.offset,
+            sampler_prev_sample: self.sampler.prev_sample[0],
+            sampler_ring_shift: self.sampler.ring_shift,
+            sampler_delay: self.sampler.delay as u32,
+            #[cfg(feature = "alloc")]
+            sampler_ring: self.sampler.read_ring(),
+            #[cfg(feature = "alloc")]
+            sampler_two_pass_offset: two_pass_offset,
+            #[cfg(feature = "alloc")]
+            sampler_two_pass_remaining: two_pass_remaining,
+            #[cfg(feature = "alloc")]
+            sampler_two_pass_ring: two_pass_ring,
         }
-        state
     }
 
-    pub fn write_state(&mut self, state: &State) {
-        for i in 0..0x19 {
-            self.write(i, state.sid_register[i as usize]);
+    /// Returns the emulator state like `read_state`, failing with
+    /// `Error::StateIncomplete` if the resampling history or pending writes
+    /// cannot be kept, i.e. without the `alloc` feature while a resampling
+    /// method is in use or writes are queued.
+    pub fn try_read_state(&self) -> Result<State, Error> {
+        if !cfg!(feature = "alloc") && (self.sampler.has_history() || !self.queue.is_empty()) {
+            return Err(Error::StateIncomplete);
         }
-        self.bus_value = state.bus_value;
-        self.bus_value_ttl = state.bus_value_ttl;
-        self.sampler.synth.ext_in = state.ext_in;
-        for i in 0..3 {
-            let envelope = &mut self.sampler.synth.voices[i].envelope;
-            self.sampler.synth.voices[i].wave.acc = state.accumulator[i];
-            self.sampler.synth.voices[i].wave.shift = state.shift_register[i];
-            envelope.state = match state.envelope_state[i] {
-                0 => EnvState::Attack,
-                1 => EnvState::DecaySustain,
-                2 => EnvState::Release,
-                _ => panic!("invalid envelope state"),
-            };
-            envelope.envelope_counter = state.envelope_counter[i];
-            envelope.exponential_counter = state.exponential_counter[i];
-            envelope.exponential_counter_period = state.exponential_counter_period[i];
-            envelope.hold_zero = state.hold_zero[i] != 0;
-            envelope.rate_counter = state.rate_counter[i];
-            envelope.rate_counter_period = state.rate_counter_period[i];
+        Ok(self.read_state())
+    }
+
+    /// Restores a state returned by `read_state`. The state is validated
+    /// first and left unapplied if any field is invalid.
+    pub fn write_state(&mut self, state: &State) -> Result<(), Error> {
+        state.validate()?;
+        let history = state.history();
+        self.sampler.check_history(&history)?;
+        state.chip.write(
+            &mut self.sampler.synth,
+            &mut self.bus_value,
+            &mut self.bus_value_ttl,
+            &mut self.queue,
+        );
+        self.cycles_elapsed = state.cycles_elapsed;
+        self.sampler.write_history(&history);
+        Ok(())
+    }
+}
+
+impl<const R: usize, const F: usize, const P: usize> Scheduled for Sid<R, F, P> {
+    fn write_pending(&mut self) {
+        while let Some((reg, value)) = self.queue.pop() {
+            self.write(reg, value);
         }
     }
+
+    fn next_write_delta(&self) -> Option<u32> {
+        self.queue.next_delta()
+    }
+
+    fn clock_direct(&mut self, delta: u32) {
+        age_bus_value(&mut self.bus_value, &mut self.bus_value_ttl, delta);
+        self.sampler.synth.clock_delta(delta);
+    }
+
+    fn clock_sampled<T: Sample>(
+        &mut self,
+        delta: u32,
+        buffer: &mut [T],
+        interleave: usize,
+    ) -> (usize, u32) {
+        self.sampler.clock(delta, buffer, interleave)
+    }
+
+    fn advance(&mut self, delta: u32) {
+        self.queue.advance(delta);
+        self.cycles_elapsed += delta as u64;
+    }
 }
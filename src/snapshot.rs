@@ -0,0 +1,375 @@
+// This file is part of resid-rs.
+// Copyright (c) 2017-2019 Sebastian Jastrzebski <sebby2k@gmail.com>. All rights reserved.
+// Portions (c) 2004 Dag Lem <resid@nimrod.no>
+// Licensed under the GPLv3. See LICENSE file in the project root for full license text.
+
+//! Binary encoding of `State`.
+//!
+//! All values are stored little endian:
+//! ``` ignore,
+//! magic     "RSID"
+//! version   u16
+//! sections  (tag: u16, length: u32, payload)*
+//! checksum  u32, CRC-32 of all preceding bytes
+//! ```
+//! Sections are self-delimiting so decoders skip sections they do not know.
+//! Missing sections leave the corresponding fields at their defaults. The
+//! layout of a known section is fixed, a change to it bumps the version.
+
+use super::error::Error;
+use super::sid::State;
+
+const MAGIC: [u8; 4] = *b"RSID";
+const VERSION: u16 = 1;
+
+const HEADER_LEN: usize = 6;
+const CHECKSUM_LEN: usize = 4;
+
+mod tag {
+    pub const SID: u16 = 1;
+    pub const VOICES: u16 = 2;
+    pub const FILTER: u16 = 3;
+    pub const EXT_FILTER: u16 = 4;
+    pub const SAMPLER: u16 = 5;
+    pub const SAMPLER_RING: u16 = 6;
+    pub const PENDING_WRITES: u16 = 7;
+    pub const SAMPLER_TWO_PASS: u16 = 8;
+}
+
+/// Writer which keeps counting past the end of the buffer, so the same
+/// code path yields the encoded length.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) {
+        if let Some(dest) = self.buf.get_mut(self.pos..self.pos + bytes.len()) {
+            dest.copy_from_slice(bytes);
+        }
+        self.pos += bytes.len();
+    }
+
+    fn put_u8(&mut self, value: u8) {
+        self.put(&[value]);
+    }
+
+    fn put_u16(&mut self, value: u16) {
+        self.put(&value.to_le_bytes());
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.put(&value.to_le_bytes());
+    }
+
+    fn put_i32(&mut self, value: i32) {
+        self.put(&value.to_le_bytes());
+    }
+
+    fn put_u64(&mut self, value: u64) {
+        self.put(&value.to_le_bytes());
+    }
+
+    fn section<F: FnOnce(&mut Self)>(&mut self, tag: u16, f: F) {
+        self.put_u16(tag);
+        let start = self.pos;
+        self.put_u32(0);
+        f(self);
+        let len = (self.pos - start - 4) as u32;
+        if let Some(dest) = self.buf.get_mut(start..start + 4) {
+            dest.copy_from_slice(&len.to_le_bytes());
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn get(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(Error::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn get_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.get(1)?[0])
+    }
+
+    fn get_u16(&mut self) -> Result<u16, Error> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.get(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    #[cfg(feature = "alloc")]
+    fn get_i16(&mut self) -> Result<i16, Error> {
+        Ok(self.get_u16()? as i16)
+    }
+
+    fn get_u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.get(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn get_i32(&mut self) -> Result<i32, Error> {
+        Ok(self.get_u32()? as i32)
+    }
+
+    fn get_u64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.get(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+}
+
+impl State {
+    /// Number of bytes needed by `encode`.
+    pub fn encoded_len(&self) -> usize {
+        self.write_to(&mut [])
+    }
+
+    /// Encodes the state into `buf`, returning the number of bytes written.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = self.write_to(buf);
+        if len > buf.len() {
+            Err(Error::BufferTooSmall)
+        } else {
+            Ok(len)
+        }
+    }
+
+    /// Decodes a state produced by `encode`. The decoded state is validated,
+    /// see `State::validate`. Without the `alloc` feature a snapshot holding
+    /// resampling history or pending writes is rejected with
+    /// `Error::StateIncomplete`.
+    pub fn decode(buf: &[u8]) -> Result<State, Error> {
+        if buf.len() < HEADER_LEN + CHECKSUM_LEN {
+            return Err(Error::Truncated);
+        }
+        if buf[..4] != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let (data, checksum) = buf.split_at(buf.len() - CHECKSUM_LEN);
+        let mut reader = Reader {
+            buf: data,
+            pos: MAGIC.len(),
+        };
+        let version = reader.get_u16()?;
+        if version > VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if crc32(data).to_le_bytes() != checksum {
+            return Err(Error::ChecksumMismatch);
+        }
+        let mut state = State::default();
+        while !reader.is_empty() {
+            let tag = reader.get_u16()?;
+            let len = reader.get_u32()? as usize;
+            let mut section = Reader {
+                buf: reader.get(len)?,
+                pos: 0,
+            };
+            state
+                .read_section(tag, &mut section)
+                .map_err(|err| match err {
+                    Error::StateIncomplete => err,
+                    _ => Error::InvalidSection(tag),
+                })?;
+        }
+        state.validate()?;
+        Ok(state)
+    }
+
+    fn write_to(&self, buf: &mut [u8]) -> usize {
+        let mut writer = Writer { buf, pos: 0 };
+        writer.put(&MAGIC);
+        writer.put_u16(VERSION);
+        writer.section(tag::SID, |w| {
+            w.put(&self.chip.sid_register);
+            w.put_u8(self.chip.bus_value);
+            w.put_u32(self.chip.bus_value_ttl);
+            w.put_i32(self.chip.ext_in);
+            w.put_u64(self.cycles_elapsed);
+        });
+        writer.section(tag::VOICES, |w| {
+            for i in 0..3 {
+                w.put_u32(self.chip.accumulator[i]);
+                w.put_u32(self.chip.shift_register[i]);
+                w.put_u8(self.chip.msb_rising[i]);
+                w.put_u8(self.chip.shift_pipeline[i]);
+                w.put_u16(self.chip.pulse_output[i]);
+                w.put_u32(self.chip.shift_register_reset[i]);
+                w.put_u16(self.chip.floating_output[i]);
+                w.put_u32(self.chip.floating_output_ttl[i]);
+                w.put_u8(self.chip.envelope_state[i]);
+                w.put_u8(self.chip.envelope_counter[i]);
+                w.put_u8(self.chip.exponential_counter[i]);
+                w.put_u8(self.chip.exponential_counter_period[i]);
+                w.put_u8(self.chip.hold_zero[i]);
+                w.put_u16(self.chip.rate_counter[i]);
+                w.put_u16(self.chip.rate_counter_period[i]);
+            }
+        });
+        writer.section(tag::FILTER, |w| {
+            w.put_i32(self.chip.filter_vhp);
+            w.put_i32(self.chip.filter_vbp);
+            w.put_i32(self.chip.filter_vlp);
+            w.put_i32(self.chip.filter_vnf);
+        });
+        writer.section(tag::EXT_FILTER, |w| {
+            w.put_i32(self.chip.ext_filter_vlp);
+            w.put_i32(self.chip.ext_filter_vhp);
+            w.put_i32(self.chip.ext_filter_vo);
+        });
+        writer.section(tag::SAMPLER, |w| {
+            w.put_i32(self.sampler_offset);
+            w.put_i32(self.sampler_prev_sample);
+            w.put_i32(self.sampler_ring_shift);
+            w.put_u32(self.sampler_delay);
+        });
+        #[cfg(feature = "alloc")]
+        {
+            writer.section(tag::SAMPLER_RING, |w| {
+                for &sample in self.sampler_ring.iter() {
+                    w.put(&sample.to_le_bytes());
+                }
+            });
+            writer.section(tag::SAMPLER_TWO_PASS, |w| {
+                w.put_u32(self.sampler_two_pass_offset);
+                w.put_u32(self.sampler_two_pass_remaining);
+                for &sample in self.sampler_two_pass_ring.iter() {
+                    w.put(&sample.to_le_bytes());
+                }
+            });
+            writer.section(tag::PENDING_WRITES, |w| {
+                for &(delta, reg, value) in self.chip.pending_writes.iter() {
+                    w.put_u32(delta);
+                    w.put_u8(reg);
+                    w.put_u8(value);
+                }
+            });
+        }
+        let len = writer.pos;
+        if let Some(data) = writer.buf.get(..len) {
+            let checksum = crc32(data);
+            writer.put_u32(checksum);
+        } else {
+            writer.pos += CHECKSUM_LEN;
+        }
+        writer.pos
+    }
+
+    fn read_section(&mut self, tag: u16, r: &mut Reader<'_>) -> Result<(), Error> {
+        match tag {
+            tag::SID => {
+                self.chip.sid_register.copy_from_slice(r.get(32)?);
+                self.chip.bus_value = r.get_u8()?;
+                self.chip.bus_value_ttl = r.get_u32()?;
+                self.chip.ext_in = r.get_i32()?;
+                self.cycles_elapsed = r.get_u64()?;
+            }
+            tag::VOICES => {
+                for i in 0..3 {
+                    self.chip.accumulator[i] = r.get_u32()?;
+                    self.chip.shift_register[i] = r.get_u32()?;
+                    self.chip.msb_rising[i] = r.get_u8()?;
+                    self.chip.shift_pipeline[i] = r.get_u8()?;
+                    self.chip.pulse_output[i] = r.get_u16()?;
+                    self.chip.shift_register_reset[i] = r.get_u32()?;
+                    self.chip.floating_output[i] = r.get_u16()?;
+                    self.chip.floating_output_ttl[i] = r.get_u32()?;
+                    self.chip.envelope_state[i] = r.get_u8()?;
+                    self.chip.envelope_counter[i] = r.get_u8()?;
+                    self.chip.exponential_counter[i] = r.get_u8()?;
+                    self.chip.exponential_counter_period[i] = r.get_u8()?;
+                    self.chip.hold_zero[i] = r.get_u8()?;
+                    self.chip.rate_counter[i] = r.get_u16()?;
+                    self.chip.rate_counter_period[i] = r.get_u16()?;
+                }
+            }
+            tag::FILTER => {
+                self.chip.filter_vhp = r.get_i32()?;
+                self.chip.filter_vbp = r.get_i32()?;
+                self.chip.filter_vlp = r.get_i32()?;
+                self.chip.filter_vnf = r.get_i32()?;
+            }
+            tag::EXT_FILTER => {
+                self.chip.ext_filter_vlp = r.get_i32()?;
+                self.chip.ext_filter_vhp = r.get_i32()?;
+                self.chip.ext_filter_vo = r.get_i32()?;
+            }
+            tag::SAMPLER => {
+                self.sampler_offset = r.get_i32()?;
+                self.sampler_prev_sample = r.get_i32()?;
+                self.sampler_ring_shift = r.get_i32()?;
+                self.sampler_delay = r.get_u32()?;
+            }
+            #[cfg(feature = "alloc")]
+            tag::SAMPLER_RING => {
+                self.sampler_ring.clear();
+                while !r.is_empty() {
+                    self.sampler_ring.push(r.get_i16()?);
+                }
+            }
+            #[cfg(feature = "alloc")]
+            tag::SAMPLER_TWO_PASS => {
+                self.sampler_two_pass_offset = r.get_u32()?;
+                self.sampler_two_pass_remaining = r.get_u32()?;
+                self.sampler_two_pass_ring.clear();
+                while !r.is_empty() {
+                    self.sampler_two_pass_ring.push(r.get_i16()?);
+                }
+            }
+            #[cfg(feature = "alloc")]
+            tag::PENDING_WRITES => {
+                self.chip.pending_writes.clear();
+                while !r.is_empty() {
+                    let delta = r.get_u32()?;
+                    let reg = r.get_u8()?;
+                    let value = r.get_u8()?;
+                    self.chip.pending_writes.push((delta, reg, value));
+                }
+            }
+            #[cfg(not(feature = "alloc"))]
+            tag::SAMPLER_RING | tag::PENDING_WRITES => {
+                if !r.is_empty() {
+                    return Err(Error::StateIncomplete);
+                }
+            }
+            #[cfg(not(feature = "alloc"))]
+            tag::SAMPLER_TWO_PASS => {
+                r.get_u32()?;
+                if r.get_u32()? != 0 || !r.is_empty() {
+                    return Err(Error::StateIncomplete);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE 802.3), computed bitwise to avoid a lookup table.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -16,12 +16,16 @@ const OUTPUT_RANGE: u32 = 1 << 16;
 const OUTPUT_HALF: i32 = (OUTPUT_RANGE >> 1) as i32;
 const SAMPLES_PER_OUTPUT: u32 = ((4095 * 255) >> 7) * 3 * 15 * 2 / OUTPUT_RANGE;
 
+// Output gain is kept as 4.12 fixpoint.
+const GAIN_SHIFT: i32 = 12;
+
 #[derive(Clone, Copy)]
 pub struct Synth {
     pub ext_filter: ExternalFilter,
     pub filter: Filter,
     pub voices: [Voice; 3],
     pub ext_in: i32,
+    gain: i32,
 }
 
 // slice::rotate_left is inefficient for small arrays:
@@ -42,6 +46,7 @@ impl Synth {
             filter: Filter::new(chip_model),
             voices: [Voice::new(chip_model); 3],
             ext_in: 0,
+            gain: 1 << GAIN_SHIFT,
         }
     }
 
@@ -78,6 +83,10 @@ impl Synth {
         for i in 0..3 {
             self.syncable_voice_mut(i).wave().synchronize();
         }
+        // Write combined noise waveforms back into the shift registers.
+        for i in 0..3 {
+            self.syncable_voice_mut(i).wave().write_shift_register();
+        }
         // Clock filter.
         self.filter.clock(
             self.syncable_voice(0).output(),
@@ -100,6 +109,11 @@ impl Synth {
             // We have to clock on each MSB on / MSB off for hard sync to operate
             // correctly.
             let mut delta_min = delta_osc;
+            // Combined noise waveforms are written back into the shift register
+            // on every cycle.
+            if self.voices.iter().any(|voice| voice.wave.writes_shift()) {
+                delta_min = 1;
+            }
             for i in 0..3 {
                 let wave = self.syncable_voice(i).wave();
                 // It is only necessary to clock on the MSB of an oscillator that is
@@ -131,6 +145,9 @@ impl Synth {
             for i in 0..3 {
                 self.syncable_voice_mut(i).wave().synchronize();
             }
+            for i in 0..3 {
+                self.syncable_voice_mut(i).wave().write_shift_register();
+            }
             delta_osc -= delta_min;
         }
         // Clock filter.
@@ -147,7 +164,7 @@ impl Synth {
 
     pub fn output(&self) -> i16 {
         // Read sample from audio output.
-        let sample = self.ext_filter.output() / SAMPLES_PER_OUTPUT as i32;
+        let sample = self.output_raw();
         if sample >= OUTPUT_HALF {
             (OUTPUT_HALF - 1) as i16
         } else if sample < -OUTPUT_HALF {
@@ -157,6 +174,23 @@ impl Synth {
         }
     }
 
+    /// Audio output scaled to 16 bits without saturation.
+    #[inline]
+    pub fn output_raw(&self) -> i32 {
+        let sample = self.ext_filter.output() / SAMPLES_PER_OUTPUT as i32;
+        ((sample as i64 * self.gain as i64) >> GAIN_SHIFT) as i32
+    }
+
+    pub fn get_gain(&self) -> f32 {
+        self.gain as f32 / (1 << GAIN_SHIFT) as f32
+    }
+
+    /// Sets the output gain, 1.0 being the nominal SID level. Values below
+    /// 1.0 provide headroom for tunes which would otherwise clip.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = (gain.max(0.0) * (1 << GAIN_SHIFT) as f32 + 0.5) as i32;
+    }
+
     pub fn reset(&mut self) {
         self.ext_filter.reset();
         self.filter.reset();
@@ -182,21 +216,21 @@ impl Synth {
             reg::FREQHI1 => self.voices[0].wave.set_frequency_hi(value),
             reg::PWLO1 => self.voices[0].wave.set_pulse_width_lo(value),
             reg::PWHI1 => self.voices[0].wave.set_pulse_width_hi(value),
-            reg::CR1 => self.voices[0].set_control(value),
+            reg::CR1 => self.syncable_voice_mut(0).set_control(value),
             reg::AD1 => self.voices[0].envelope.set_attack_decay(value),
             reg::SR1 => self.voices[0].envelope.set_sustain_release(value),
             reg::FREQLO2 => self.voices[1].wave.set_frequency_lo(value),
             reg::FREQHI2 => self.voices[1].wave.set_frequency_hi(value),
             reg::PWLO2 => self.voices[1].wave.set_pulse_width_lo(value),
             reg::PWHI2 => self.voices[1].wave.set_pulse_width_hi(value),
-            reg::CR2 => self.voices[1].set_control(value),
+            reg::CR2 => self.syncable_voice_mut(1).set_control(value),
             reg::AD2 => self.voices[1].envelope.set_attack_decay(value),
             reg::SR2 => self.voices[1].envelope.set_sustain_release(value),
             reg::FREQLO3 => self.voices[2].wave.set_frequency_lo(value),
             reg::FREQHI3 => self.voices[2].wave.set_frequency_hi(value),
             reg::PWLO3 => self.voices[2].wave.set_pulse_width_lo(value),
             reg::PWHI3 => self.voices[2].wave.set_pulse_width_hi(value),
-            reg::CR3 => self.voices[2].set_control(value),
+            reg::CR3 => self.syncable_voice_mut(2).set_control(value),
             reg::AD3 => self.voices[2].envelope.set_attack_decay(value),
             reg::SR3 => self.voices[2].envelope.set_sustain_release(value),
             reg::FCLO => self.filter.set_fc_lo(value),
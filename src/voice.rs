@@ -5,6 +5,7 @@
 
 #![cfg_attr(feature = "cargo-clippy", allow(clippy::cast_lossless))]
 
+use super::data;
 use super::envelope::EnvelopeGenerator;
 use super::wave::{Syncable, WaveformGenerator};
 use super::ChipModel;
@@ -40,6 +41,9 @@ pub struct Voice {
     // Configuration
     wave_zero: i32,
     voice_dc: i32,
+    wave_dac: &'static [u16; 4096],
+    env_dac: &'static [u16; 256],
+    dac_model: bool,
     // Generators
     pub envelope: EnvelopeGenerator,
     pub wave: WaveformGenerator,
@@ -51,6 +55,9 @@ impl Voice {
             ChipModel::Mos6581 => Voice {
                 wave_zero: WAVE_ZERO,
                 voice_dc: VOICE_DC,
+                wave_dac: &data::DAC6581_WAVE,
+                env_dac: &data::DAC6581_ENV,
+                dac_model: false,
                 envelope: EnvelopeGenerator::default(),
                 wave: WaveformGenerator::new(chip_model),
             },
@@ -58,6 +65,9 @@ impl Voice {
                 // No DC offsets in the MOS8580.
                 wave_zero: 0x800,
                 voice_dc: 0,
+                wave_dac: &data::DAC8580_WAVE,
+                env_dac: &data::DAC8580_ENV,
+                dac_model: false,
                 envelope: EnvelopeGenerator::default(),
                 wave: WaveformGenerator::new(chip_model),
             },
@@ -69,13 +79,28 @@ impl Voice {
         self.wave.set_control(value);
     }
 
+    /// Models the waveform and envelope DACs as R-2R ladders with the bit
+    /// weights of the chip model, instead of as ideal converters.
+    pub fn set_dac_model(&mut self, enabled: bool) {
+        self.dac_model = enabled;
+    }
+
     /// Amplitude modulated 20-bit waveform output.
     /// Range [-2048*255, 2047*255].
     #[inline]
     pub fn output(&self, sync_source: Option<&WaveformGenerator>) -> i32 {
+        let wave = self.wave.output(sync_source);
+        let env = self.envelope.output();
+        let (wave, env) = if self.dac_model {
+            (
+                self.wave_dac[wave as usize],
+                self.env_dac[env as usize] as u8,
+            )
+        } else {
+            (wave, env)
+        };
         // Multiply oscillator output with envelope output.
-        (self.wave.output(sync_source) as i32 - self.wave_zero) * self.envelope.output() as i32
-            + self.voice_dc
+        (wave as i32 - self.wave_zero) * env as i32 + self.voice_dc
     }
 
     pub fn reset(&mut self) {
@@ -101,6 +126,11 @@ impl<'a> Syncable<&'a Voice> {
 }
 
 impl<'a> Syncable<&'a mut Voice> {
+    pub fn set_control(self, value: u8) {
+        self.main.envelope.set_control(value);
+        self.wave().set_control(value);
+    }
+
     pub fn wave(self) -> Syncable<&'a mut WaveformGenerator> {
         Syncable {
             main: &mut self.main.wave,
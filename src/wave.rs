@@ -14,8 +14,20 @@ const ACC_MASK: u32 = 0x00ff_ffff;
 const ACC_BIT19_MASK: u32 = 0x0008_0000;
 const ACC_MSB_MASK: u32 = 0x0080_0000;
 const SHIFT_MASK: u32 = 0x007f_ffff;
+const SHIFT_OUTPUT_MASK: u32 = 0x0051_2894;
 const OUTPUT_MASK: u16 = 0x0fff;
 
+// Cycles the test bit must be held for the shift register to reset.
+const SHIFT_RESET_6581: u32 = 35_000;
+pub(crate) const SHIFT_RESET_8580: u32 = 2_519_864;
+
+// Cycles the waveform DAC keeps floating at the last output level, and
+// cycles between the bits fading out afterwards.
+const FLOATING_OUTPUT_TTL_6581: u32 = 54_000;
+const FLOATING_OUTPUT_TTL_8580: u32 = 800_000;
+const FLOATING_OUTPUT_FADE_6581: u32 = 1_400;
+const FLOATING_OUTPUT_FADE_8580: u32 = 50_000;
+
 /// A 24 bit accumulator is the basis for waveform generation. FREQ is added to
 /// the lower 16 bits of the accumulator each cycle.
 /// The accumulator is set to zero when TEST is set, and starts counting
@@ -32,10 +44,21 @@ pub struct WaveformGenerator {
     ring: bool,
     sync: bool,
     test: bool,
+    pipeline_delays: bool,
+    shift_reset_delay: bool,
+    floating_output_enabled: bool,
+    shift_reset_period: u32,
+    floating_output_ttl_period: u32,
+    floating_output_fade_period: u32,
     // Runtime State
     pub acc: u32,
     pub shift: u32,
-    msb_rising: bool,
+    pub msb_rising: bool,
+    pub shift_pipeline: u8,
+    pub pulse_output: u16,
+    pub shift_reset: u32,
+    pub floating_output: u16,
+    pub floating_output_ttl: u32,
     // Static Data
     wave_ps: &'static [u8; 4096],
     wave_pst: &'static [u8; 4096],
@@ -51,20 +74,26 @@ pub struct Syncable<T> {
 
 impl WaveformGenerator {
     pub fn new(chip_model: ChipModel) -> Self {
-        let (wave_ps, wave_pst, wave_pt, wave_st) = match chip_model {
+        let (wave_ps, wave_pst, wave_pt, wave_st, shift_reset_period) = match chip_model {
             ChipModel::Mos6581 => (
                 &data::WAVE6581_PS,
                 &data::WAVE6581_PST,
                 &data::WAVE6581_PT,
                 &data::WAVE6581_ST,
+                SHIFT_RESET_6581,
             ),
             ChipModel::Mos8580 => (
                 &data::WAVE8580_PS,
                 &data::WAVE8580_PST,
                 &data::WAVE8580_PT,
                 &data::WAVE8580_ST,
+                SHIFT_RESET_8580,
             ),
         };
+        let (floating_output_ttl_period, floating_output_fade_period) = match chip_model {
+            ChipModel::Mos6581 => (FLOATING_OUTPUT_TTL_6581, FLOATING_OUTPUT_FADE_6581),
+            ChipModel::Mos8580 => (FLOATING_OUTPUT_TTL_8580, FLOATING_OUTPUT_FADE_8580),
+        };
         let mut waveform = WaveformGenerator {
             frequency: 0,
             pulse_width: 0,
@@ -72,9 +101,20 @@ impl WaveformGenerator {
             ring: false,
             sync: false,
             test: false,
+            pipeline_delays: false,
+            shift_reset_delay: false,
+            floating_output_enabled: false,
+            shift_reset_period,
+            floating_output_ttl_period,
+            floating_output_fade_period,
             acc: 0,
             shift: 0,
             msb_rising: false,
+            shift_pipeline: 0,
+            pulse_output: 0,
+            shift_reset: 0,
+            floating_output: 0,
+            floating_output_ttl: 0,
             wave_ps,
             wave_pst,
             wave_pt,
@@ -132,30 +172,114 @@ impl WaveformGenerator {
         self.acc = value;
     }
 
+    /// Models the delays between the accumulator and the noise and pulse
+    /// outputs: the shift register is clocked 2 cycles after bit 19 is set
+    /// high, and the pulse comparator output is latched for 1 cycle.
+    pub fn set_pipeline_delays(&mut self, enabled: bool) {
+        if self.shift_pipeline != 0 {
+            self.shift_pipeline = 0;
+            self.clock_shift();
+        }
+        self.pipeline_delays = enabled;
+        self.pulse_output = self.compare_pulse();
+    }
+
+    /// Models the time the test bit must be held for the shift register to
+    /// reset, and the shift completed when the test bit is cleared. Off by
+    /// default, the shift register is then cleared at once by the test bit
+    /// and set to 0x7ffff8 when it is cleared, like in reSID 0.16.
+    pub fn set_shift_reset_delay(&mut self, enabled: bool) {
+        self.shift_reset_delay = enabled;
+        self.shift_reset = 0;
+    }
+
+    /// Models the output floating at the last level when the waveform is
+    /// cleared and its fading afterwards, see `set_floating_output_ttl` and
+    /// `set_floating_output_fade`. Off by default, the output then drops to
+    /// zero at once like in reSID 0.16.
+    pub fn set_floating_output(&mut self, enabled: bool) {
+        self.floating_output_enabled = enabled;
+        if !enabled {
+            self.floating_output = 0;
+            self.floating_output_ttl = 0;
+        }
+    }
+
+    /// Sets the number of cycles the output keeps floating at the last level
+    /// when no waveform is selected, 0 dropping it to zero at once.
+    pub fn set_floating_output_ttl(&mut self, cycles: u32) {
+        self.floating_output_ttl_period = cycles;
+        self.floating_output_ttl = self.floating_output_ttl.min(cycles);
+        if self.floating_output_ttl == 0 {
+            self.floating_output = 0;
+        }
+    }
+
+    /// Sets the number of cycles between the fading steps of the floating
+    /// output, 0 dropping it to zero at the end of the floating time. Takes
+    /// effect with the next step.
+    pub fn set_floating_output_fade(&mut self, cycles: u32) {
+        self.floating_output_fade_period = cycles;
+    }
+
     pub fn set_control(&mut self, value: u8) {
-        self.waveform = (value >> 4) & 0x0f;
+        let waveform = (value >> 4) & 0x0f;
+        if waveform == 0
+            && self.waveform != 0
+            && self.floating_output_enabled
+            && self.floating_output_ttl_period != 0
+        {
+            // Waveform cleared.
+            // The waveform DAC input is left floating, so the output stays at the
+            // last level until the charge starts to leak away, see
+            // `clock_floating_output`.
+            self.floating_output = self.output(None);
+            self.floating_output_ttl = self.floating_output_ttl_period;
+        } else if waveform != 0 {
+            self.floating_output = 0;
+            self.floating_output_ttl = 0;
+        }
+        self.waveform = waveform;
         self.sync = value.get_bit(1);
         self.ring = value.get_bit(2);
         let test = value.get_bit(3);
-        if test {
+        if test && !self.test {
             // Test bit set.
-            // The accumulator and the shift register are both cleared.
-            // NB! The shift register is not really cleared immediately. It seems like
-            // the individual bits in the shift register start to fade down towards
-            // zero when test is set. All bits reach zero within approximately
-            // $2000 - $4000 cycles.
-            // This is not modeled. There should fortunately be little audible output
-            // from this peculiar behavior.
             self.acc = 0;
-            self.shift = 0;
-        } else if self.test {
+            self.shift_pipeline = 0;
+            self.pulse_output = 0x0fff;
+            if self.shift_reset_delay {
+                // The accumulator is cleared, while the shift register is prepared
+                // for shifting by interconnecting the register bits. The bits start
+                // to rise towards one and reach it after a chip model dependent
+                // number of cycles, see `clock`.
+                self.shift_reset = self.shift_reset_period;
+            } else {
+                // The accumulator and the shift register are both cleared.
+                // NB! The shift register is not really cleared immediately. It seems like
+                // the individual bits in the shift register start to fade down towards
+                // zero when test is set. All bits reach zero within approximately
+                // $2000 - $4000 cycles.
+                // This is not modeled. There should fortunately be little audible output
+                // from this peculiar behavior.
+                self.shift = 0;
+            }
+        } else if !test && self.test {
             // Test bit cleared.
-            // The accumulator starts counting, and the shift register is reset to
-            // the value 0x7ffff8.
-            // NB! The shift register will not actually be set to this exact value if the
-            // shift register bits have not had time to fade to zero.
-            // This is not modeled.
-            self.shift = 0x007f_fff8;
+            if self.shift_reset_delay {
+                // The accumulator starts counting, and the pending shift is completed.
+                // The test bit is or'ed into bit 22 on the way, so bit 0 is set to the
+                // inverse of bit 17.
+                let bit0 = (!self.shift >> 17) & 0x01;
+                self.shift = ((self.shift << 1) & SHIFT_MASK) | bit0;
+            } else {
+                // The accumulator starts counting, and the shift register is reset to
+                // the value 0x7ffff8.
+                // NB! The shift register will not actually be set to this exact value if the
+                // shift register bits have not had time to fade to zero.
+                // This is not modeled.
+                self.shift = 0x007f_fff8;
+            }
         }
         self.test = test;
     }
@@ -182,8 +306,27 @@ impl WaveformGenerator {
 
     #[inline]
     pub fn clock(&mut self) {
-        // No operation if test bit is set.
-        if !self.test {
+        if self.floating_output_ttl != 0 {
+            self.clock_floating_output(1);
+        }
+        self.clock_acc();
+    }
+
+    #[inline]
+    fn clock_acc(&mut self) {
+        if self.pipeline_delays {
+            // Latch the comparator output of the previous cycle.
+            self.pulse_output = self.compare_pulse();
+        }
+        if self.test {
+            // Count down the time to fully reset the shift register.
+            if self.shift_reset != 0 {
+                self.shift_reset -= 1;
+                if self.shift_reset == 0 {
+                    self.shift = SHIFT_MASK;
+                }
+            }
+        } else {
             let acc_prev = self.acc;
             // Calculate new accumulator value;
             self.acc = (self.acc + self.frequency as u32) & ACC_MASK;
@@ -191,15 +334,67 @@ impl WaveformGenerator {
             self.msb_rising = (acc_prev & ACC_MSB_MASK) == 0 && (self.acc & ACC_MSB_MASK) != 0;
             if (acc_prev & ACC_BIT19_MASK) == 0 && (self.acc & ACC_BIT19_MASK) != 0 {
                 // Shift noise register once for each time accumulator bit 19 is set high.
-                let bit0 = ((self.shift >> 22) ^ (self.shift >> 17)) & 0x01;
-                self.shift = ((self.shift << 1) & SHIFT_MASK) | bit0;
+                if self.pipeline_delays {
+                    self.shift_pipeline = 2;
+                } else {
+                    self.clock_shift();
+                }
+            } else if self.shift_pipeline != 0 {
+                self.shift_pipeline -= 1;
+                if self.shift_pipeline == 0 {
+                    self.clock_shift();
+                }
             }
         }
     }
 
     #[inline]
     pub fn clock_delta(&mut self, delta: u32) {
-        if !self.test {
+        if self.floating_output_ttl != 0 {
+            self.clock_floating_output(delta);
+        }
+        if self.pipeline_delays && delta != 0 && !self.test {
+            // Bit 19 cannot be set high twice within 2 cycles, so the pending
+            // shift is completed first and the last 2 cycles are clocked one
+            // by one to leave the pipeline and the pulse latch in place.
+            let acc_prev = self.acc;
+            let tail = delta.min(2);
+            let head = delta - tail;
+            if self.shift_pipeline != 0 {
+                if head >= self.shift_pipeline as u32 {
+                    self.shift_pipeline = 0;
+                    self.clock_shift();
+                } else {
+                    self.shift_pipeline -= head as u8;
+                }
+            }
+            self.clock_acc_delta(head);
+            for _ in 0..tail {
+                self.clock_acc();
+            }
+            self.msb_rising = (acc_prev & ACC_MSB_MASK) == 0 && (self.acc & ACC_MSB_MASK) != 0;
+        } else {
+            if self.pipeline_delays && delta != 0 {
+                self.pulse_output = self.compare_pulse();
+            }
+            self.clock_acc_delta(delta);
+        }
+    }
+
+    /// Adds `delta` cycles to the accumulator, shifting the noise register
+    /// without delay.
+    #[inline]
+    fn clock_acc_delta(&mut self, delta: u32) {
+        if self.test {
+            if self.shift_reset != 0 {
+                if delta >= self.shift_reset {
+                    self.shift_reset = 0;
+                    self.shift = SHIFT_MASK;
+                } else {
+                    self.shift_reset -= delta;
+                }
+            }
+        } else {
             let acc_prev = self.acc;
             // Calculate new accumulator value;
             let mut delta_acc = delta * self.frequency as u32;
@@ -229,17 +424,56 @@ impl WaveformGenerator {
                     }
                 }
                 // Shift the noise/random register.
-                let bit0 = ((self.shift >> 22) ^ (self.shift >> 17)) & 0x01;
-                self.shift = (self.shift << 1) & SHIFT_MASK | bit0;
+                self.clock_shift();
                 delta_acc -= shift_period;
             }
         }
     }
 
+    /// Fades out the floating output. Once the floating time has passed,
+    /// each fade period clears every bit whose next higher bit is cleared,
+    /// so the output decays in steps rather than dropping to zero at once.
+    #[inline]
+    fn clock_floating_output(&mut self, mut delta: u32) {
+        while delta >= self.floating_output_ttl {
+            delta -= self.floating_output_ttl;
+            self.floating_output &= self.floating_output >> 1;
+            if self.floating_output == 0 || self.floating_output_fade_period == 0 {
+                self.floating_output = 0;
+                self.floating_output_ttl = 0;
+                return;
+            }
+            self.floating_output_ttl = self.floating_output_fade_period;
+        }
+        self.floating_output_ttl -= delta;
+    }
+
+    #[inline]
+    fn clock_shift(&mut self) {
+        let bit0 = ((self.shift >> 22) ^ (self.shift >> 17)) & 0x01;
+        self.shift = ((self.shift << 1) & SHIFT_MASK) | bit0;
+    }
+
     /// 12-bit waveform output
     #[inline]
     pub fn output(&self, sync_source: Option<&WaveformGenerator>) -> u16 {
         match self.waveform {
+            0x0 => self.floating_output,
+            0x8 => self.output_n(),
+            0x9..=0xf => self.output_n() & self.output_wave(self.waveform & 0x7, sync_source),
+            waveform => self.output_wave(waveform, sync_source),
+        }
+    }
+
+    /// Whether the waveform output is written back into the shift register,
+    /// see `Syncable::write_shift_register`.
+    pub fn writes_shift(&self) -> bool {
+        self.waveform > 0x8 && !self.test
+    }
+
+    #[inline]
+    fn output_wave(&self, waveform: u8, sync_source: Option<&WaveformGenerator>) -> u16 {
+        match waveform {
             0x0 => 0,
             0x1 => self.output_t(sync_source),
             0x2 => self.output_s(),
@@ -248,15 +482,7 @@ impl WaveformGenerator {
             0x5 => self.output_pt(sync_source),
             0x6 => self.output_ps(),
             0x7 => self.output_pst(),
-            0x8 => self.output_n(),
-            0x9 => 0,
-            0xa => 0,
-            0xb => 0,
-            0xc => 0,
-            0xd => 0,
-            0xe => 0,
-            0xf => 0,
-            _ => panic!("invalid waveform {}", self.waveform),
+            _ => panic!("invalid waveform {}", waveform),
         }
     }
 
@@ -270,6 +496,11 @@ impl WaveformGenerator {
         self.acc = 0;
         self.shift = 0x007f_fff8;
         self.msb_rising = false;
+        self.shift_pipeline = 0;
+        self.pulse_output = 0;
+        self.shift_reset = 0;
+        self.floating_output = 0;
+        self.floating_output_ttl = 0;
     }
 
     // -- Output Functions
@@ -278,7 +509,7 @@ impl WaveformGenerator {
     /// The noise output is taken from intermediate bits of a 23-bit shift register
     /// which is clocked by bit 19 of the accumulator.
     /// NB! The output is actually delayed 2 cycles after bit 19 is set high.
-    /// This is not modeled.
+    /// This is only modeled with pipeline delays enabled.
     ///
     /// Operation: Calculate EOR result, shift register, set bit 0 = result.
     ///``` ignore,
@@ -309,12 +540,21 @@ impl WaveformGenerator {
     /// These bits are compared to the pulse width register by a 12 bit digital
     /// comparator; output is either all one or all zero bits.
     /// NB! The output is actually delayed one cycle after the compare.
-    /// This is not modeled.
+    /// This is only modeled with pipeline delays enabled.
     ///
     /// The test bit, when set to one, holds the pulse waveform output at 0xfff
     /// regardless of the pulse width setting.
     #[inline]
     fn output_p(&self) -> u16 {
+        if self.pipeline_delays {
+            self.pulse_output
+        } else {
+            self.compare_pulse()
+        }
+    }
+
+    #[inline]
+    fn compare_pulse(&self) -> u16 {
         if self.test || ((self.acc >> 12) as u16 >= self.pulse_width) {
             0x0fff
         } else {
@@ -349,6 +589,27 @@ impl WaveformGenerator {
 
     // -- Combined Waveforms
 
+    /// Noise combined with other waveforms:
+    /// The combined output pulls the shift register bits feeding the noise
+    /// output low, since these drive the waveform DAC together with the other
+    /// waveforms. A bit once cleared stays cleared until it is shifted out,
+    /// which eventually locks up the noise generator at zero.
+    /// The noise output bits are thus and'ed with the other waveforms and
+    /// written back into the shift register.
+    #[inline]
+    fn write_shift(&mut self, output: u16) {
+        let output = output as u32;
+        self.shift &= !SHIFT_OUTPUT_MASK
+            | ((output & 0x0800) << 11)
+            | ((output & 0x0400) << 10)
+            | ((output & 0x0200) << 7)
+            | ((output & 0x0100) << 5)
+            | ((output & 0x0080) << 4)
+            | ((output & 0x0040) << 1)
+            | ((output & 0x0020) >> 1)
+            | ((output & 0x0010) >> 2);
+    }
+
     #[inline]
     fn output_ps(&self) -> u16 {
         ((self.wave_ps[self.output_s() as usize] as u16) << 4) & self.output_p()
@@ -377,6 +638,27 @@ impl Syncable<&'_ WaveformGenerator> {
 }
 
 impl Syncable<&'_ mut WaveformGenerator> {
+    /// Sets the control register, keeping the ring modulated output floating
+    /// if the waveform is cleared.
+    pub fn set_control(&mut self, value: u8) {
+        let output = self.main.output(Some(self.sync_source));
+        self.main.set_control(value);
+        if self.main.floating_output_ttl != 0 {
+            self.main.floating_output = output;
+        }
+    }
+
+    /// Write combined waveforms including noise back into the shift register.
+    /// Like synchronization this must be done after all the oscillators have
+    /// been clock()'ed, since ring modulation depends on the sync source.
+    #[inline]
+    pub fn write_shift_register(&mut self) {
+        if self.main.writes_shift() {
+            let output = self.main.output(Some(self.sync_source));
+            self.main.write_shift(output);
+        }
+    }
+
     /// Synchronize oscillators.
     /// This must be done after all the oscillators have been clock()'ed since the
     /// oscillators operate in parallel.
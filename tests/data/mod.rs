@@ -1,3 +1,4 @@
+pub mod osc3_output;
 pub mod sid_output;
 pub mod spline_output;
 pub mod wave_delta_output;
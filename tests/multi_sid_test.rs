@@ -0,0 +1,250 @@
+use resid::{ChipModel, Error, MultiSid, SamplingMethod, Sid};
+
+const CPU_FREQ: u32 = 985248;
+const SAMPLE_FREQ: u32 = 44100;
+const SAMPLE_COUNT: usize = 512;
+const CYCLES_PER_SAMPLE: u32 = CPU_FREQ / SAMPLE_FREQ;
+
+fn setup(write: &mut dyn FnMut(u8, u8), freq: u16) {
+    write(0x00, (freq & 0x00ff) as u8); // FREQ_LO
+    write(0x01, (freq >> 8) as u8); // FREQ_HI
+    write(0x02, 0x00); // PW_LO
+    write(0x03, 0x08); // PW_HI
+    write(0x05, 0x09); // ATTACK_DECAY
+    write(0x06, 0xf0); // SUSTAIN_RELEASE
+    write(0x04, 0x41); // CONTROL
+    write(0x18, 0x0f); // MODE_VOL
+}
+
+fn compare_single_chip(method: SamplingMethod) {
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    sid.set_sampling_parameters(method, CPU_FREQ, SAMPLE_FREQ)
+        .unwrap();
+    setup(&mut |reg, value| sid.write(reg, value), 0x1cd6);
+    let mut expected = [0i16; SAMPLE_COUNT];
+    let (samples, _) = sid.sample(SAMPLE_COUNT as u32 * CYCLES_PER_SAMPLE, &mut expected, 1);
+
+    let mut multi_sid = MultiSid::new([ChipModel::Mos6581]);
+    multi_sid
+        .set_sampling_parameters(method, CPU_FREQ, SAMPLE_FREQ)
+        .unwrap();
+    setup(
+        &mut |reg, value| multi_sid.write(0, reg, value).unwrap(),
+        0x1cd6,
+    );
+    let mut buffer = [0i16; SAMPLE_COUNT * 2];
+    let (frames, _) = multi_sid.sample(SAMPLE_COUNT as u32 * CYCLES_PER_SAMPLE, &mut buffer);
+
+    assert_eq!(frames, samples);
+    for i in 0..frames {
+        assert_eq!(buffer[i * 2], expected[i]);
+        assert_eq!(buffer[i * 2 + 1], expected[i]);
+    }
+}
+
+#[test]
+fn single_chip_fast() {
+    compare_single_chip(SamplingMethod::Fast);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn single_chip_resample() {
+    compare_single_chip(SamplingMethod::Resample);
+}
+
+#[test]
+fn pan() {
+    let mut multi_sid = MultiSid::new([ChipModel::Mos6581, ChipModel::Mos8580]);
+    setup(
+        &mut |reg, value| multi_sid.write(0, reg, value).unwrap(),
+        0x1cd6,
+    );
+    setup(
+        &mut |reg, value| multi_sid.write(1, reg, value).unwrap(),
+        0x2000,
+    );
+    multi_sid.set_pan(0, -1.0).unwrap();
+    multi_sid.set_pan(1, 1.0).unwrap();
+    multi_sid.set_gain(1, 0.0).unwrap();
+    let mut buffer = [0i16; SAMPLE_COUNT * 2];
+    let (frames, _) = multi_sid.sample(SAMPLE_COUNT as u32 * CYCLES_PER_SAMPLE, &mut buffer);
+    assert!(frames > 0);
+    let frames = &buffer[..frames * 2];
+    assert!(frames.chunks(2).any(|frame| frame[0] != 0));
+    assert!(frames.chunks(2).all(|frame| frame[1] == 0));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn master_gain() {
+    let run = |master_gain: f32| {
+        let mut multi_sid = MultiSid::new([ChipModel::Mos8580, ChipModel::Mos8580]);
+        multi_sid
+            .set_sampling_parameters(SamplingMethod::Resample, CPU_FREQ, SAMPLE_FREQ)
+            .unwrap();
+        multi_sid.set_master_gain(master_gain);
+        setup(
+            &mut |reg, value| multi_sid.write(0, reg, value).unwrap(),
+            0x1cd6,
+        );
+        setup(
+            &mut |reg, value| multi_sid.write(1, reg, value).unwrap(),
+            0x1cd6,
+        );
+        let mut buffer = [0i16; SAMPLE_COUNT * 2];
+        multi_sid.sample(SAMPLE_COUNT as u32 * CYCLES_PER_SAMPLE, &mut buffer);
+        multi_sid.output_stats()
+    };
+    let stats = run(1.0);
+    assert_eq!(stats.clipped, 0);
+    let half = run(0.5);
+    assert_eq!(half.clipped, 0);
+    assert!((half.peak as i32 - stats.peak as i32 / 2).abs() <= 2);
+    assert!(run(8.0).clipped > 0);
+}
+
+fn sample_single(chip_model: ChipModel, freq: u16, gain: f32, buffer: &mut [i16]) -> usize {
+    let mut sid = Sid::new(chip_model);
+    sid.set_gain(gain);
+    setup(&mut |reg, value| sid.write(reg, value), freq);
+    sid.sample(SAMPLE_COUNT as u32 * CYCLES_PER_SAMPLE, buffer, 1)
+        .0
+}
+
+#[test]
+fn pan_gain_mix() {
+    let mut expected_0 = [0i16; SAMPLE_COUNT];
+    let samples = sample_single(ChipModel::Mos6581, 0x1cd6, 1.0, &mut expected_0);
+    let mut expected_1 = [0i16; SAMPLE_COUNT];
+    sample_single(ChipModel::Mos8580, 0x2000, 0.5, &mut expected_1);
+    let mut expected_1_full = [0i16; SAMPLE_COUNT];
+    sample_single(ChipModel::Mos8580, 0x2000, 1.0, &mut expected_1_full);
+
+    let run = |pan: [f32; 2], gain: [f32; 2], buffer: &mut [i16]| {
+        let mut multi_sid = MultiSid::new([ChipModel::Mos6581, ChipModel::Mos8580]);
+        setup(
+            &mut |reg, value| multi_sid.write(0, reg, value).unwrap(),
+            0x1cd6,
+        );
+        setup(
+            &mut |reg, value| multi_sid.write(1, reg, value).unwrap(),
+            0x2000,
+        );
+        for chip in 0..2 {
+            multi_sid.set_pan(chip, pan[chip]).unwrap();
+            multi_sid.set_gain(chip, gain[chip]).unwrap();
+        }
+        multi_sid
+            .sample(SAMPLE_COUNT as u32 * CYCLES_PER_SAMPLE, buffer)
+            .0
+    };
+
+    // Hard panned chips end up on one channel each, at their own gain.
+    let mut buffer = [0i16; SAMPLE_COUNT * 2];
+    let frames = run([-1.0, 1.0], [1.0, 0.5], &mut buffer);
+    assert_eq!(frames, samples);
+    assert!(expected_0[..samples].iter().any(|&sample| sample != 0));
+    assert!(expected_1[..samples].iter().any(|&sample| sample != 0));
+    for i in 0..frames {
+        assert_eq!(buffer[i * 2], expected_0[i]);
+        assert_eq!(buffer[i * 2 + 1], expected_1[i]);
+    }
+
+    // Centered chips are summed on both channels, a chip moved to the
+    // right is attenuated on the left.
+    let frames = run([0.0, 0.5], [1.0, 1.0], &mut buffer);
+    assert_eq!(frames, samples);
+    for i in 0..frames {
+        let left = expected_0[i] as i32 + expected_1[i] as i32;
+        let right = expected_0[i] as i32 + expected_1_full[i] as i32;
+        assert!((buffer[i * 2] as i32 - left).abs() <= 1);
+        assert_eq!(buffer[i * 2 + 1] as i32, right);
+    }
+}
+
+#[test]
+fn invalid_chip() {
+    let mut multi_sid = MultiSid::new([ChipModel::Mos6581, ChipModel::Mos8580]);
+    assert_eq!(multi_sid.set_gain(2, 0.5), Err(Error::InvalidChip));
+    assert_eq!(multi_sid.set_pan(2, 0.5), Err(Error::InvalidChip));
+    assert!(!multi_sid.write_at(2, 100, 0x18, 0x0f));
+    assert_eq!(multi_sid.write(2, 0x18, 0x0f), Err(Error::InvalidChip));
+    assert_eq!(multi_sid.read(2, 0x1b), Err(Error::InvalidChip));
+    assert_eq!(multi_sid.read(1, 0x19), Ok(0xff));
+}
+
+#[test]
+fn write_at() {
+    let mut expected = MultiSid::new([ChipModel::Mos6581, ChipModel::Mos8580]);
+    setup(
+        &mut |reg, value| expected.write(0, reg, value).unwrap(),
+        0x1cd6,
+    );
+    let mut buffer_expected = [0i16; SAMPLE_COUNT * 2];
+    let (frames, _) = expected.sample(1000, &mut buffer_expected);
+    setup(
+        &mut |reg, value| expected.write(1, reg, value).unwrap(),
+        0x2000,
+    );
+    let (rest, _) = expected.sample(10_000, &mut buffer_expected[frames * 2..]);
+
+    let mut multi_sid = MultiSid::new([ChipModel::Mos6581, ChipModel::Mos8580]);
+    setup(
+        &mut |reg, value| multi_sid.write(0, reg, value).unwrap(),
+        0x1cd6,
+    );
+    setup(
+        &mut |reg, value| assert!(multi_sid.write_at(1, 1000, reg, value)),
+        0x2000,
+    );
+    let mut buffer = [0i16; SAMPLE_COUNT * 2];
+    let (frames_total, _) = multi_sid.sample(11_000, &mut buffer);
+    assert_eq!(frames_total, frames + rest);
+    assert_eq!(&buffer[..], &buffer_expected[..]);
+    assert_eq!(multi_sid.read_state(), expected.read_state());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn state_roundtrip() {
+    let setup_sid = || {
+        let mut multi_sid = MultiSid::new([ChipModel::Mos6581, ChipModel::Mos8580]);
+        multi_sid
+            .set_sampling_parameters(SamplingMethod::Resample, CPU_FREQ, SAMPLE_FREQ)
+            .unwrap();
+        multi_sid.set_pan(0, -0.5).unwrap();
+        multi_sid
+    };
+    let mut multi_sid = setup_sid();
+    setup(
+        &mut |reg, value| multi_sid.write(0, reg, value).unwrap(),
+        0x1cd6,
+    );
+    setup(
+        &mut |reg, value| multi_sid.write(1, reg, value).unwrap(),
+        0x2000,
+    );
+    let mut buffer = [0i16; SAMPLE_COUNT * 2];
+    multi_sid.sample(5000, &mut buffer);
+    assert!(multi_sid.write_at(1, 300, 0x04, 0x40));
+    let state = multi_sid.read_state();
+    assert!(!state.sampler_ring.is_empty());
+    assert_eq!(state.chips[1].pending_writes.len(), 1);
+
+    let mut restored = setup_sid();
+    restored.write_state(&state).unwrap();
+    assert_eq!(restored.read_state(), state);
+    let mut buffer_expected = [0i16; SAMPLE_COUNT * 2];
+    let (frames_expected, _) = multi_sid.sample(5000, &mut buffer_expected);
+    let (frames, _) = restored.sample(5000, &mut buffer);
+    assert_eq!(frames, frames_expected);
+    assert_eq!(&buffer[..frames * 2], &buffer_expected[..frames * 2]);
+
+    let mut corrupted = state.clone();
+    corrupted.chips[1].envelope_state[0] = 3;
+    assert_eq!(
+        restored.write_state(&corrupted),
+        Err(Error::InvalidState("envelope_state"))
+    );
+}
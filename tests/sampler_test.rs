@@ -0,0 +1,163 @@
+use resid::sampler::Sampler;
+#[cfg(feature = "alloc")]
+use resid::sampler::{SamplingMethod, Source};
+use resid::synth::Synth;
+use resid::{ChipModel, Error, Simd};
+
+/// Pseudo random values in [-range, range), range being a power of two.
+fn generate(len: usize, seed: u32, range: i32) -> Vec<i16> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (((state >> 16) as i32 & (2 * range - 1)) - range) as i16
+        })
+        .collect()
+}
+
+type Convolution<'a> = dyn Fn(&Sampler, &[i16], &[i16]) -> i32 + 'a;
+
+fn compare_convolution_fir(convolve: &Convolution<'_>) {
+    let sampler = Sampler::new(Synth::new(ChipModel::Mos6581));
+    for len in (0..=130).chain([255, 300].iter().cloned()) {
+        let samples = generate(len + 3, len as u32, 32768);
+        let fir = generate(len, !(len as u32), 128);
+        let expected = sampler.compute_convolution_fir_fallback(&samples, &fir);
+        assert_eq!(convolve(&sampler, &samples, &fir), expected);
+        assert_eq!(convolve(&sampler, &fir, &samples), expected);
+    }
+}
+
+#[test]
+fn convolution_fir() {
+    compare_convolution_fir(&|sampler, samples, fir| sampler.compute_convolution_fir(samples, fir));
+}
+
+#[test]
+fn convolution_fir_simd() {
+    let simds = [
+        Simd::Scalar,
+        Simd::Sse42,
+        Simd::Avx2,
+        Simd::Neon,
+        Simd::Simd128,
+    ];
+    for &simd in simds.iter() {
+        let mut sampler = Sampler::new(Synth::new(ChipModel::Mos6581));
+        if simd.is_supported() {
+            assert_eq!(sampler.set_simd(simd), Ok(()));
+            assert_eq!(sampler.simd(), simd);
+            compare_convolution_fir(&|_, samples, fir| {
+                sampler.compute_convolution_fir(samples, fir)
+            });
+        } else {
+            assert_eq!(sampler.set_simd(simd), Err(Error::UnsupportedSimd));
+            assert_eq!(sampler.simd(), Simd::detect());
+        }
+    }
+    assert!(Simd::detect().is_supported());
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[test]
+fn convolution_fir_avx2() {
+    if is_x86_feature_detected!("avx2") {
+        compare_convolution_fir(&|sampler, samples, fir| unsafe {
+            sampler.compute_convolution_fir_avx2(samples, fir)
+        });
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[test]
+fn convolution_fir_sse() {
+    if is_x86_feature_detected!("sse4.2") {
+        compare_convolution_fir(&|sampler, samples, fir| unsafe {
+            sampler.compute_convolution_fir_sse(samples, fir)
+        });
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[test]
+fn convolution_fir_neon() {
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        compare_convolution_fir(&|sampler, samples, fir| unsafe {
+            sampler.compute_convolution_fir_neon(samples, fir)
+        });
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+#[test]
+fn convolution_fir_simd128() {
+    compare_convolution_fir(&|sampler, samples, fir| {
+        sampler.compute_convolution_fir_simd128(samples, fir)
+    });
+}
+
+#[cfg(feature = "alloc")]
+/// Sine tone at the SID clock.
+struct Tone {
+    phase: f64,
+    step: f64,
+}
+
+#[cfg(feature = "alloc")]
+impl Source<1> for Tone {
+    fn clock(&mut self) {
+        self.phase += self.step;
+    }
+
+    fn clock_delta(&mut self, delta: u32) {
+        self.phase += self.step * delta as f64;
+    }
+
+    fn output_frame(&self) -> [i32; 1] {
+        [(16384.0 * self.phase.sin()) as i32]
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// Output level in dB of a tone sampled at 44.1 kHz, relative to the tone.
+fn tone_level(method: SamplingMethod, freq: f64) -> f64 {
+    let clock_freq = 985_248;
+    let mut sampler = Sampler::new(Tone {
+        phase: 0.0,
+        step: 2.0 * core::f64::consts::PI * freq / clock_freq as f64,
+    });
+    sampler.set_parameters(method, clock_freq, 44100).unwrap();
+    let mut buffer = [0i16; 22050];
+    let (samples, _) = sampler.clock(clock_freq / 2, &mut buffer, 1);
+    // Skip the filter startup.
+    let tail = &buffer[samples / 2..samples];
+    let power = tail.iter().map(|&v| (v as f64).powi(2)).sum::<f64>() / tail.len() as f64;
+    10.0 * (power / (16384.0f64.powi(2) / 2.0)).log10()
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn two_pass_aliasing() {
+    let levels = |freq| {
+        (
+            tone_level(SamplingMethod::Resample, freq),
+            tone_level(SamplingMethod::ResampleTwoPass, freq),
+        )
+    };
+    // The passband is kept alike.
+    let (resample, two_pass) = levels(1000.0);
+    assert!(resample > -0.5 && (resample - two_pass).abs() < 0.1);
+    // Above half the sampling frequency two-pass resampling attenuates far
+    // less than Resample. A tone right above it folds into the transition
+    // band, tones further up leak into the whole output band.
+    for &freq in [23050.0, 30000.0].iter() {
+        let (resample, two_pass) = levels(freq);
+        assert!(resample < -55.0, "{} Hz: {} dB", freq, resample);
+        assert!(two_pass < -20.0, "{} Hz: {} dB", freq, two_pass);
+        assert!(two_pass > resample + 20.0, "{} Hz: {} dB", freq, two_pass);
+    }
+}
@@ -1,6 +1,9 @@
 mod data;
 
-use resid::{ChipModel, Sid};
+use resid::sampler::{fir_table_len, RING_SIZE};
+use resid::{ChipModel, Error, ResampleParams, SamplingMethod, Sid};
+#[cfg(feature = "alloc")]
+use resid::{FirCache, FirTable};
 
 #[rustfmt::skip]
 static SID_DATA: [u16; 51] = [
@@ -41,3 +44,927 @@ fn clock_delta() {
         i += 3;
     }
 }
+
+#[cfg(feature = "alloc")]
+#[test]
+fn write_at() {
+    let writes: [(u32, u8, u8); 6] = [
+        (0, 0x18, 0x0f),
+        (0, 0x05, 0x09),
+        (137, 0x01, 25),
+        (137, 0x00, 177),
+        (1000, 0x04, 0x41),
+        (3333, 0x03, 0x08),
+    ];
+    let methods = [
+        SamplingMethod::Fast,
+        SamplingMethod::Interpolate,
+        SamplingMethod::Resample,
+        SamplingMethod::ResampleFast,
+    ];
+    for &method in methods.iter() {
+        let mut expected = Sid::new(ChipModel::Mos6581);
+        expected
+            .set_sampling_parameters(method, 985_248, 44100)
+            .unwrap();
+        let mut buffer_expected = [0i16; 256];
+        let mut index = 0;
+        let mut cycle = 0;
+        for &(write_cycle, reg, value) in writes.iter() {
+            let (samples, _) =
+                expected.sample(write_cycle - cycle, &mut buffer_expected[index..], 1);
+            index += samples;
+            cycle = write_cycle;
+            expected.write(reg, value);
+        }
+        let _ = expected.sample(5000 - cycle, &mut buffer_expected[index..], 1);
+
+        let mut sid = Sid::new(ChipModel::Mos6581);
+        sid.set_sampling_parameters(method, 985_248, 44100).unwrap();
+        for &(write_cycle, reg, value) in writes.iter() {
+            assert!(sid.write_at(write_cycle, reg, value));
+        }
+        let mut buffer = [0i16; 256];
+        let _ = sid.sample(5000, &mut buffer, 1);
+        assert_eq!(&buffer[..], &buffer_expected[..]);
+        assert_eq!(
+            sid.read_state().chip.sid_register[..0x19],
+            expected.read_state().chip.sid_register[..0x19]
+        );
+    }
+}
+
+#[test]
+fn write_at_time_base_wrap() {
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    sid.enable_filter(false);
+    sid.enable_external_filter(false);
+    // Slow envelopes keep clocking billions of cycles cheap.
+    for &reg in [0x06, 0x0d, 0x14].iter() {
+        sid.write(reg, 0x0f);
+    }
+    let freq_lo = |sid: &Sid| sid.read_state().chip.sid_register[0x00];
+    // Keep a write queued while the 32-bit queue time base wraps.
+    assert!(sid.write_at(3_000_000_000, 0x00, 1));
+    sid.clock_delta(2_999_999_000);
+    assert!(sid.write_at(2000, 0x00, 2));
+    sid.clock_delta(1500);
+    assert_eq!(freq_lo(&sid), 1);
+    assert!(sid.write_at(2_000_000_000, 0x00, 3));
+    sid.clock_delta(500);
+    assert_eq!(freq_lo(&sid), 1);
+    sid.clock();
+    assert_eq!(freq_lo(&sid), 2);
+    sid.clock_delta(2_000_000_000 - 502);
+    assert_eq!(freq_lo(&sid), 2);
+    sid.clock();
+    assert_eq!(freq_lo(&sid), 2);
+    sid.clock();
+    assert_eq!(freq_lo(&sid), 3);
+}
+
+fn compare_sample_f32(method: SamplingMethod, tolerance: f32) {
+    let setup = |sid: &mut Sid| {
+        sid.set_sampling_parameters(method, 985_248, 44100).unwrap();
+        sid.write(0x05, 0x09); // AD1
+        sid.write(0x06, 0xf0); // SR1
+        sid.write(0x18, 0x0f); // MODVOL
+        sid.write(0x01, 25); // FREQHI1
+        sid.write(0x00, 177); // FREQLO1
+        sid.write(0x04, 0x21); // CR1
+    };
+    let mut expected = Sid::new(ChipModel::Mos6581);
+    setup(&mut expected);
+    let mut buffer_expected = [0i16; 256];
+    let (samples_expected, _) = expected.sample(5000, &mut buffer_expected, 1);
+
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    setup(&mut sid);
+    let mut buffer = [0f32; 256];
+    let (samples, _) = sid.sample_f32(5000, &mut buffer, 1);
+    assert_eq!(samples, samples_expected);
+    for i in 0..samples {
+        let sample = buffer_expected[i] as f32 / 32768.0;
+        assert!((buffer[i] - sample).abs() <= tolerance);
+    }
+}
+
+#[test]
+fn sample_f32_fast() {
+    compare_sample_f32(SamplingMethod::Fast, 0.0);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn sample_f32_resample() {
+    compare_sample_f32(SamplingMethod::Resample, 4.0 / 32768.0);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn sample_f32_resample_two_pass() {
+    compare_sample_f32(SamplingMethod::ResampleTwoPass, 4.0 / 32768.0);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn sample_f32_resample_headroom() {
+    let run = |gain: f32, buffer_i16: &mut [i16], buffer_f32: &mut [f32]| {
+        let mut sid = Sid::new(ChipModel::Mos8580);
+        sid.set_sampling_parameters(SamplingMethod::Resample, 985_248, 44100)
+            .unwrap();
+        sid.set_gain(gain);
+        for voice in 0..3 {
+            sid.write(voice * 7 + 0x05, 0x00); // AD
+            sid.write(voice * 7 + 0x06, 0xf0); // SR
+            sid.write(voice * 7 + 0x01, 25); // FREQHI
+            sid.write(voice * 7 + 0x04, 0x11); // CR
+        }
+        sid.write(0x18, 0x0f); // MODVOL
+        if buffer_f32.is_empty() {
+            sid.sample(10000, buffer_i16, 1).0
+        } else {
+            sid.sample_f32(10000, buffer_f32, 1).0
+        }
+    };
+    // Above 16-bit full scale the floating point output follows the
+    // unsaturated 16-bit output at half the gain.
+    let mut expected = [0i16; 512];
+    let samples_expected = run(1.25, &mut expected, &mut []);
+    let mut buffer = [0f32; 512];
+    let samples = run(2.5, &mut [], &mut buffer);
+    assert_eq!(samples, samples_expected);
+    for i in 0..samples {
+        let sample = expected[i] as f32 * 2.0 / 32768.0;
+        assert!((buffer[i] - sample).abs() <= 8.0 / 32768.0);
+    }
+    assert!(buffer[..samples].iter().any(|&sample| sample.abs() > 1.2));
+    // The ring saturates at twice 16-bit full scale.
+    let samples = run(4.0, &mut [], &mut buffer);
+    assert!(buffer[..samples].iter().all(|&sample| sample.abs() <= 2.0));
+}
+
+fn setup_tone<const R: usize, const F: usize, const P: usize>(sid: &mut Sid<R, F, P>) {
+    sid.write(0x05, 0x00); // AD1
+    sid.write(0x06, 0xf0); // SR1
+    sid.write(0x18, 0x0f); // MODVOL
+    sid.write(0x01, 25); // FREQHI1
+    sid.write(0x00, 177); // FREQLO1
+    sid.write(0x04, 0x11); // CR1
+}
+
+#[cfg(feature = "alloc")]
+fn sample_tone(method: SamplingMethod, buffer: &mut [i16]) -> usize {
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    sid.set_sampling_parameters(method, 985_248, 44100).unwrap();
+    setup_tone(&mut sid);
+    sid.sample(100_000, buffer, 1).0
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn resample_two_pass() {
+    let mut expected = [0i16; 8192];
+    let samples_expected = sample_tone(SamplingMethod::Resample, &mut expected);
+    let mut buffer = [0i16; 8192];
+    let samples = sample_tone(SamplingMethod::ResampleTwoPass, &mut buffer);
+    assert!((samples as i32 - samples_expected as i32).abs() <= 1);
+    // Both filters pass the tone unaltered, only their delay differs so the
+    // peaks are sampled at a different phase.
+    let range = |buffer: &[i16]| {
+        let min = *buffer.iter().min().unwrap() as i32;
+        let max = *buffer.iter().max().unwrap() as i32;
+        (min, max)
+    };
+    let (min_expected, max_expected) = range(&expected[1000..samples_expected]);
+    let (min, max) = range(&buffer[1000..samples]);
+    assert!((min - min_expected).abs() <= 160);
+    assert!((max - max_expected).abs() <= 160);
+}
+
+#[test]
+fn output_stats() {
+    let run = |gain: f32| {
+        let mut sid = Sid::new(ChipModel::Mos6581);
+        sid.set_gain(gain);
+        sid.write(0x05, 0x00); // AD1
+        sid.write(0x06, 0xf0); // SR1
+        sid.write(0x18, 0x0f); // MODVOL
+        sid.write(0x01, 25); // FREQHI1
+        sid.write(0x00, 177); // FREQLO1
+        sid.write(0x04, 0x21); // CR1
+        let mut buffer = [0i16; 512];
+        let (samples, _) = sid.sample(10000, &mut buffer, 1);
+        let peak = buffer[..samples]
+            .iter()
+            .map(|sample| (*sample as i32).unsigned_abs())
+            .max()
+            .unwrap();
+        (sid.output_stats(), peak)
+    };
+    let (stats, peak) = run(1.0);
+    assert_eq!(stats.clipped, 0);
+    assert_eq!(stats.peak, peak);
+    let (stats, _) = run(8.0);
+    assert!(stats.clipped > 0);
+    assert!(stats.peak > 32768);
+    let (stats, _) = run(0.5);
+    assert_eq!(stats.clipped, 0);
+    assert!(stats.peak <= peak / 2 + 1);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn output_stats_resample() {
+    let run = |gain: f32, waveform: u8| {
+        let mut sid = Sid::new(ChipModel::Mos8580);
+        sid.set_sampling_parameters(SamplingMethod::Resample, 985_248, 44100)
+            .unwrap();
+        sid.set_gain(gain);
+        for voice in 0..3 {
+            sid.write(voice * 7 + 0x05, 0x00); // AD
+            sid.write(voice * 7 + 0x06, 0xf0); // SR
+            sid.write(voice * 7 + 0x01, 25); // FREQHI
+            sid.write(voice * 7 + 0x04, waveform); // CR
+        }
+        sid.write(0x18, 0x0f); // MODVOL
+        let mut buffer = [0i16; 512];
+        sid.sample(10000, &mut buffer, 1);
+        sid.output_stats()
+    };
+    assert_eq!(run(1.0, 0x21).clipped, 0);
+    // The loud chip saturates the resampling ring as well as the output.
+    let stats = run(8.0, 0x21);
+    assert!(stats.clipped > 0);
+    assert!(stats.peak >= 32768);
+    // A triangle saturated in the ring is smoothed by the filter so the
+    // output stays in range, yet the clipping is still reported.
+    let stats = run(3.0, 0x11);
+    assert!(stats.clipped > 0);
+    assert!(stats.peak < 32768);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn state_roundtrip() {
+    compare_state_roundtrip(SamplingMethod::Resample);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn state_roundtrip_two_pass() {
+    compare_state_roundtrip(SamplingMethod::ResampleTwoPass);
+}
+
+#[cfg(feature = "alloc")]
+fn compare_state_roundtrip(method: SamplingMethod) {
+    let setup = |sid: &mut Sid| {
+        sid.set_sampling_parameters(method, 985_248, 44100).unwrap();
+    };
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    setup(&mut sid);
+    sid.write(0x05, 0x09); // AD1
+    sid.write(0x06, 0xf0); // SR1
+    sid.write(0x15, 0x00); // FCLO
+    sid.write(0x16, 0x40); // FCHI
+    sid.write(0x17, 0xf1); // RESFILT
+    sid.write(0x18, 0x1f); // MODVOL
+    sid.write(0x01, 25); // FREQHI1
+    sid.write(0x00, 177); // FREQLO1
+    sid.write(0x04, 0x41); // CR1
+    let mut buffer = [0i16; 512];
+    let _ = sid.sample(3000, &mut buffer, 1);
+    assert!(sid.write_at(1500, 0x04, 0x40));
+
+    let state = sid.read_state();
+    let mut restored = Sid::new(ChipModel::Mos6581);
+    setup(&mut restored);
+    restored.write_state(&state).unwrap();
+    assert_eq!(restored.read_state(), state);
+
+    let mut buffer_restored = [0i16; 512];
+    let (samples, _) = sid.sample(5000, &mut buffer, 1);
+    let (samples_restored, _) = restored.sample(5000, &mut buffer_restored, 1);
+    assert_eq!(samples_restored, samples);
+    assert_eq!(&buffer_restored[..samples], &buffer[..samples]);
+}
+
+#[test]
+fn set_sampling_parameters_invalid() {
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    assert_eq!(
+        sid.set_sampling_parameters(SamplingMethod::Fast, 985_248, 0),
+        Err(Error::InvalidFrequency)
+    );
+    assert_eq!(
+        sid.set_sampling_parameters(SamplingMethod::Fast, 0, 44100),
+        Err(Error::InvalidFrequency)
+    );
+    assert_eq!(
+        sid.set_sampling_parameters(SamplingMethod::Interpolate, 44100, 985_248),
+        Err(Error::UnsupportedSamplingRatio)
+    );
+    assert_eq!(
+        sid.set_sampling_parameters(SamplingMethod::Resample, 985_248, 4000),
+        Err(Error::UnsupportedSamplingRatio)
+    );
+    assert_eq!(
+        sid.set_sampling_parameters(SamplingMethod::Fast, 985_248, 4000),
+        Ok(())
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn set_sampling_parameters_with() {
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    let default = ResampleParams::default();
+    assert_eq!(
+        sid.set_sampling_parameters_with(SamplingMethod::Resample, 985_248, 44100, default),
+        sid.set_sampling_parameters(SamplingMethod::Resample, 985_248, 44100)
+    );
+    let wide = ResampleParams {
+        pass_freq: 40000.0,
+        ..default
+    };
+    assert_eq!(
+        sid.set_sampling_parameters_with(SamplingMethod::Resample, 985_248, 96000, wide),
+        Ok(())
+    );
+    assert_eq!(
+        sid.set_sampling_parameters_with(SamplingMethod::Resample, 985_248, 44100, wide),
+        Err(Error::PassbandTooWide)
+    );
+    assert_eq!(
+        sid.set_sampling_parameters_with(SamplingMethod::ResampleTwoPass, 985_248, 44100, wide),
+        Err(Error::PassbandTooWide)
+    );
+    let cheap = ResampleParams {
+        attenuation: 48.0,
+        ..default
+    };
+    assert_eq!(
+        sid.set_sampling_parameters_with(SamplingMethod::Resample, 985_248, 22050, cheap),
+        Ok(())
+    );
+    let loud = ResampleParams {
+        filter_scale: 1.1,
+        ..default
+    };
+    assert_eq!(
+        sid.set_sampling_parameters_with(SamplingMethod::Resample, 985_248, 44100, loud),
+        Err(Error::InvalidFilterScale)
+    );
+    let weak = ResampleParams {
+        attenuation: 10.0,
+        ..default
+    };
+    assert_eq!(
+        sid.set_sampling_parameters_with(SamplingMethod::Resample, 985_248, 44100, weak),
+        Err(Error::InvalidAttenuation)
+    );
+    for &method in [SamplingMethod::Resample, SamplingMethod::ResampleTwoPass].iter() {
+        for &attenuation in [121.0, 1e300, f64::INFINITY, f64::NAN].iter() {
+            let strong = ResampleParams {
+                attenuation,
+                ..default
+            };
+            assert_eq!(
+                sid.set_sampling_parameters_with(method, 985_248, 44100, strong),
+                Err(Error::InvalidAttenuation)
+            );
+            assert_eq!(
+                fir_table_len(method, 985_248, 44100, strong),
+                Err(Error::InvalidAttenuation)
+            );
+        }
+        let empty = ResampleParams {
+            pass_freq: 0.0,
+            ..default
+        };
+        assert_eq!(
+            sid.set_sampling_parameters_with(method, 985_248, 44100, empty),
+            Err(Error::PassbandEmpty)
+        );
+    }
+    // The longest filters are rejected before their length overflows.
+    let sharp = ResampleParams {
+        pass_freq: 0.9 * 8000.0 / 2.0,
+        attenuation: 120.0,
+        ..default
+    };
+    assert_eq!(
+        sid.set_sampling_parameters_with(SamplingMethod::Resample, 985_248 * 4, 8000, sharp),
+        Err(Error::UnsupportedSamplingRatio)
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn set_sampling_parameters_inline() {
+    const FIR_STORAGE: usize = 48 * 1024;
+    let default = ResampleParams::default();
+    assert_eq!(
+        fir_table_len(SamplingMethod::Fast, 985_248, 44100, default),
+        Ok(0)
+    );
+    for &method in [SamplingMethod::Resample, SamplingMethod::ResampleTwoPass].iter() {
+        let len = fir_table_len(method, 985_248, 44100, default).unwrap();
+        assert!(len > 0 && len <= FIR_STORAGE);
+        let mut sid = Sid::<RING_SIZE, 1024>::with_capacity(ChipModel::Mos6581);
+        assert_eq!(
+            sid.set_sampling_parameters_inline(method, 985_248, 44100, default),
+            Err(Error::BufferTooSmall)
+        );
+        let mut sid = Sid::<RING_SIZE, FIR_STORAGE>::with_capacity(ChipModel::Mos6581);
+        sid.set_sampling_parameters_inline(method, 985_248, 44100, default)
+            .unwrap();
+        let mut expected = [0i16; 4096];
+        let samples_expected = sample_tone(method, &mut expected);
+        setup_tone(&mut sid);
+        let mut buffer = [0i16; 4096];
+        let (samples, _) = sid.sample(100_000, &mut buffer, 1);
+        assert_eq!(samples, samples_expected);
+        assert_eq!(&buffer[..samples], &expected[..samples]);
+    }
+}
+
+#[cfg(not(feature = "alloc"))]
+#[test]
+fn set_sampling_parameters_no_alloc() {
+    const FIR_STORAGE: usize = 48 * 1024;
+    let default = ResampleParams::default();
+    for &method in [SamplingMethod::Resample, SamplingMethod::ResampleTwoPass].iter() {
+        // Without a heap the filter tables must fit the inline storage.
+        let mut sid = Sid::new(ChipModel::Mos6581);
+        assert_eq!(
+            sid.set_sampling_parameters(method, 985_248, 44100),
+            Err(Error::BufferTooSmall)
+        );
+        let mut sid = Sid::<RING_SIZE, 1024>::with_capacity(ChipModel::Mos6581);
+        assert_eq!(
+            sid.set_sampling_parameters_inline(method, 985_248, 44100, default),
+            Err(Error::BufferTooSmall)
+        );
+        let len = fir_table_len(method, 985_248, 44100, default).unwrap();
+        assert!(len > 1024 && len <= FIR_STORAGE);
+
+        let mut expected = Sid::<RING_SIZE, FIR_STORAGE>::with_capacity(ChipModel::Mos6581);
+        expected
+            .set_sampling_parameters(method, 985_248, 44100)
+            .unwrap();
+        setup_tone(&mut expected);
+        let mut buffer_expected = [0i16; 4096];
+        let (samples_expected, _) = expected.sample(100_000, &mut buffer_expected, 1);
+        let mut sid = Sid::<RING_SIZE, FIR_STORAGE>::with_capacity(ChipModel::Mos6581);
+        sid.set_sampling_parameters_inline(method, 985_248, 44100, default)
+            .unwrap();
+        setup_tone(&mut sid);
+        let mut buffer = [0i16; 4096];
+        let (samples, _) = sid.sample(100_000, &mut buffer, 1);
+        assert_eq!(samples, samples_expected);
+        assert_eq!(&buffer[..samples], &buffer_expected[..samples]);
+        assert!(buffer[..samples].iter().any(|&sample| sample != 0));
+    }
+    // The Resample filter of about 2800 cycles needs a ring of 4096.
+    let mut sid = Sid::<4095, FIR_STORAGE>::with_capacity(ChipModel::Mos6581);
+    assert_eq!(
+        sid.set_sampling_parameters(SamplingMethod::Resample, 985_248, 44100),
+        Err(Error::BufferTooSmall)
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn set_sampling_parameters_shared() {
+    let default = ResampleParams::default();
+    let mut cache = FirCache::new();
+    for &method in [SamplingMethod::Resample, SamplingMethod::ResampleTwoPass].iter() {
+        let table = cache.get(method, 985_248, 44100, default).unwrap();
+        let mut expected = [0i16; 4096];
+        let samples_expected = sample_tone(method, &mut expected);
+        for _ in 0..2 {
+            let mut sid = Sid::new(ChipModel::Mos6581);
+            sid.set_sampling_parameters_shared(
+                &cache.get(method, 985_248, 44100, default).unwrap(),
+            )
+            .unwrap();
+            setup_tone(&mut sid);
+            let mut buffer = [0i16; 4096];
+            let (samples, _) = sid.sample(100_000, &mut buffer, 1);
+            assert_eq!(samples, samples_expected);
+            assert_eq!(&buffer[..samples], &expected[..samples]);
+        }
+        assert!(table.method() == method);
+    }
+    assert_eq!(cache.len(), 2);
+    assert_eq!(
+        cache
+            .get(SamplingMethod::Resample, 985_248, 4000, default)
+            .err(),
+        Some(Error::UnsupportedSamplingRatio)
+    );
+    assert_eq!(cache.len(), 2);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn ring_sized_to_filter() {
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    assert!(std::mem::size_of::<Sid>() < 16 * 1024);
+    assert!(sid.read_state().sampler_ring.is_empty());
+    sid.set_sampling_parameters(SamplingMethod::Resample, 985_248, 44100)
+        .unwrap();
+    let len = sid.read_state().sampler_ring.len();
+    assert!(len.is_power_of_two() && len < 16384);
+    // History of another ring size is cut to the most recent samples.
+    let mut state = sid.read_state();
+    state.sampler_ring = (0..16384).map(|i| i as i16).collect();
+    sid.write_state(&state).unwrap();
+    assert_eq!(
+        sid.read_state().sampler_ring[..],
+        state.sampler_ring[16384 - len..]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn ring_capacity() {
+    let mut sid = Sid::<0>::with_capacity(ChipModel::Mos6581);
+    assert_eq!(
+        sid.set_sampling_parameters(SamplingMethod::Resample, 985_248, 44100),
+        Err(Error::BufferTooSmall)
+    );
+    let mut expected = [0i16; 512];
+    let samples_expected = sample_tone(SamplingMethod::Interpolate, &mut expected);
+    sid.set_sampling_parameters(SamplingMethod::Interpolate, 985_248, 44100)
+        .unwrap();
+    setup_tone(&mut sid);
+    let mut buffer = [0i16; 512];
+    assert_eq!(sid.sample(100_000, &mut buffer, 1).0, samples_expected);
+    assert_eq!(buffer, expected);
+
+    // The Resample filter of about 2800 cycles needs a ring of 4096.
+    let table = FirTable::new(
+        SamplingMethod::Resample,
+        985_248,
+        44100,
+        ResampleParams::default(),
+    )
+    .unwrap();
+    let mut sid = Sid::<4095>::with_capacity(ChipModel::Mos6581);
+    assert_eq!(
+        sid.set_sampling_parameters(SamplingMethod::Resample, 985_248, 44100),
+        Err(Error::BufferTooSmall)
+    );
+    assert_eq!(
+        sid.set_sampling_parameters_shared(&table),
+        Err(Error::BufferTooSmall)
+    );
+    let mut sid = Sid::<4096>::with_capacity(ChipModel::Mos6581);
+    sid.set_sampling_parameters_shared(&table).unwrap();
+    let mut expected = [0i16; 4096];
+    let samples_expected = sample_tone(SamplingMethod::Resample, &mut expected);
+    setup_tone(&mut sid);
+    let mut buffer = [0i16; 4096];
+    let (samples, _) = sid.sample(100_000, &mut buffer, 1);
+    assert_eq!(samples, samples_expected);
+    assert_eq!(&buffer[..samples], &expected[..samples]);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn two_pass_ring_capacity() {
+    // The first step filter of about 100 cycles needs a ring of 128, the
+    // second step filter of about 150 intermediate samples a ring of 256.
+    let table = FirTable::new(
+        SamplingMethod::ResampleTwoPass,
+        985_248,
+        44100,
+        ResampleParams::default(),
+    )
+    .unwrap();
+    let mut sid = Sid::<128, 0, 255>::with_capacity(ChipModel::Mos6581);
+    assert_eq!(
+        sid.set_sampling_parameters(SamplingMethod::ResampleTwoPass, 985_248, 44100),
+        Err(Error::BufferTooSmall)
+    );
+    assert_eq!(
+        sid.set_sampling_parameters_shared(&table),
+        Err(Error::BufferTooSmall)
+    );
+    let mut sid = Sid::<128, 0, 256>::with_capacity(ChipModel::Mos6581);
+    sid.set_sampling_parameters_shared(&table).unwrap();
+    let mut expected = [0i16; 4096];
+    let samples_expected = sample_tone(SamplingMethod::ResampleTwoPass, &mut expected);
+    setup_tone(&mut sid);
+    let mut buffer = [0i16; 4096];
+    let (samples, _) = sid.sample(100_000, &mut buffer, 1);
+    assert_eq!(samples, samples_expected);
+    assert_eq!(&buffer[..samples], &expected[..samples]);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn change_sampling_parameters() {
+    let max_step = |buffer: &[i16]| {
+        buffer
+            .windows(2)
+            .map(|w| (w[1] as i32 - w[0] as i32).abs())
+            .max()
+            .unwrap()
+    };
+    for &method in [SamplingMethod::Resample, SamplingMethod::ResampleTwoPass].iter() {
+        let mut sid = Sid::new(ChipModel::Mos6581);
+        sid.set_sampling_parameters(method, 985_248, 44100).unwrap();
+        setup_tone(&mut sid);
+        let mut buffer = [0i16; 8192];
+        let (before, _) = sid.sample(50_000, &mut buffer, 1);
+        let default = ResampleParams::default();
+        sid.change_sampling_parameters(method, 985_248, 48000, default)
+            .unwrap();
+        let (after, _) = sid.sample(20_000, &mut buffer[before..], 1);
+        let step = max_step(&buffer[before / 2..before]);
+        // The tone continues without a dropout or a skip in time.
+        assert!(max_step(&buffer[before - 1..before + after]) <= step * 2);
+
+        // The history delay is part of the state.
+        let state = sid.read_state();
+        assert!(state.sampler_delay > 0);
+        let mut restored = Sid::new(ChipModel::Mos6581);
+        restored
+            .set_sampling_parameters(method, 985_248, 48000)
+            .unwrap();
+        restored.write_state(&state).unwrap();
+        let mut expected = [0i16; 512];
+        let (samples, _) = sid.sample(5000, &mut expected, 1);
+        let (samples_restored, _) = restored.sample(5000, &mut buffer, 1);
+        assert_eq!(samples_restored, samples);
+        assert_eq!(&buffer[..samples], &expected[..samples]);
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn set_rate_ratio() {
+    let methods = [
+        SamplingMethod::Fast,
+        SamplingMethod::Interpolate,
+        SamplingMethod::Resample,
+        SamplingMethod::ResampleFast,
+        SamplingMethod::ResampleTwoPass,
+    ];
+    let mut cache = FirCache::new();
+    for &method in methods.iter() {
+        let table = cache
+            .get(method, 985_248, 44100, ResampleParams::default())
+            .unwrap();
+        let count = |ratio: f64| {
+            let mut sid = Sid::new(ChipModel::Mos6581);
+            sid.set_sampling_parameters_shared(&table).unwrap();
+            sid.set_rate_ratio(ratio).unwrap();
+            setup_tone(&mut sid);
+            let mut buffer = vec![0i16; 8192];
+            let (samples, delta) = sid.sample(20_000, &mut buffer, 1);
+            assert_eq!(delta, 0);
+            samples as f64
+        };
+        let nominal = count(1.0);
+        assert!((nominal - 20_000.0 * 44100.0 / 985_248.0).abs() <= 2.0);
+        assert!((count(1.01) - nominal * 1.01).abs() <= 2.0);
+        assert!((count(0.99) - nominal * 0.99).abs() <= 2.0);
+    }
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    assert_eq!(sid.set_rate_ratio(1.2), Err(Error::InvalidRateRatio));
+    assert_eq!(sid.set_rate_ratio(f64::NAN), Err(Error::InvalidRateRatio));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn max_latency() {
+    let default = ResampleParams::default();
+    let bounded = ResampleParams {
+        max_latency: Some(200),
+        ..default
+    };
+    for &method in [SamplingMethod::Resample, SamplingMethod::ResampleTwoPass].iter() {
+        assert!(
+            fir_table_len(method, 985_248, 44100, bounded).unwrap()
+                < fir_table_len(method, 985_248, 44100, default).unwrap()
+        );
+        // The shorter filter lets the tone through earlier.
+        let onset = |params: ResampleParams| {
+            let mut sid = Sid::new(ChipModel::Mos6581);
+            sid.set_sampling_parameters_with(method, 985_248, 44100, params)
+                .unwrap();
+            setup_tone(&mut sid);
+            let mut buffer = [0i16; 512];
+            let (samples, _) = sid.sample(10_000, &mut buffer, 1);
+            let start = buffer[0];
+            buffer[..samples]
+                .iter()
+                .position(|&sample| (sample as i32 - start as i32).abs() > 1000)
+                .unwrap()
+        };
+        assert!(onset(bounded) + 10 < onset(default));
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn max_latency_too_low() {
+    for &method in [SamplingMethod::Resample, SamplingMethod::ResampleTwoPass].iter() {
+        for &max_latency in [0, 1].iter() {
+            let params = ResampleParams {
+                max_latency: Some(max_latency),
+                ..ResampleParams::default()
+            };
+            assert_eq!(
+                fir_table_len(method, 985_248, 44100, params),
+                Err(Error::LatencyTooLow)
+            );
+            let mut sid = Sid::new(ChipModel::Mos6581);
+            assert_eq!(
+                sid.set_sampling_parameters_with(method, 985_248, 44100, params),
+                Err(Error::LatencyTooLow)
+            );
+        }
+        // A short filter still passes the tone.
+        let params = ResampleParams {
+            max_latency: Some(200),
+            ..ResampleParams::default()
+        };
+        let mut sid = Sid::new(ChipModel::Mos6581);
+        sid.set_sampling_parameters_with(method, 985_248, 44100, params)
+            .unwrap();
+        setup_tone(&mut sid);
+        let mut buffer = [0i16; 512];
+        let (samples, _) = sid.sample(10_000, &mut buffer, 1);
+        let (min, max) = buffer[..samples]
+            .iter()
+            .fold((i16::MAX, i16::MIN), |(min, max), &s| {
+                (min.min(s), max.max(s))
+            });
+        assert!(max as i32 - min as i32 > 1000);
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn cycles_elapsed_and_latency() {
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    sid.clock();
+    sid.clock_delta(99);
+    let mut buffer = [0i16; 16];
+    let (_, delta_rem) = sid.sample(10_000, &mut buffer, 1);
+    assert!(delta_rem > 0);
+    assert_eq!(sid.cycles_elapsed(), 100 + 10_000 - delta_rem as u64);
+    sid.reset();
+    assert_eq!(sid.cycles_elapsed(), 100 + 10_000 - delta_rem as u64);
+    let state = sid.read_state();
+    let mut restored = Sid::new(ChipModel::Mos6581);
+    restored.write_state(&state).unwrap();
+    assert_eq!(restored.cycles_elapsed(), sid.cycles_elapsed());
+
+    assert_eq!(sid.output_latency_cycles(), 0);
+    sid.set_sampling_parameters(SamplingMethod::Resample, 985_248, 44100)
+        .unwrap();
+    let latency = sid.output_latency_cycles();
+    assert!(latency > 1300 && latency < 1500);
+    let params = ResampleParams {
+        max_latency: Some(200),
+        ..ResampleParams::default()
+    };
+    sid.set_sampling_parameters_with(SamplingMethod::ResampleTwoPass, 985_248, 44100, params)
+        .unwrap();
+    assert!(sid.output_latency_cycles() <= 200);
+}
+
+#[test]
+fn osc3_pipeline_delays_regression() {
+    // The fixtures pin the modeled timing, they are not hardware captures.
+    for &clock_delta in [false, true].iter() {
+        let clock = |sid: &mut Sid| {
+            if clock_delta {
+                sid.clock_delta(1);
+            } else {
+                sid.clock();
+            }
+        };
+        let mut sid = Sid::new(ChipModel::Mos6581);
+        sid.enable_pipeline_delays(true);
+        sid.write(0x0f, 0x80); // FREQHI3
+        sid.write(0x12, 0x80); // CR3, noise
+        for (i, &osc3) in data::osc3_output::OSC3_NOISE_OUTPUT.iter().enumerate() {
+            clock(&mut sid);
+            assert_eq!(sid.read(0x1b), osc3, "noise cycle {}", i);
+        }
+
+        let mut sid = Sid::new(ChipModel::Mos6581);
+        sid.enable_pipeline_delays(true);
+        sid.write(0x0f, 0x80); // FREQHI3
+        sid.write(0x12, 0x40); // CR3, pulse
+        for (i, &osc3) in data::osc3_output::OSC3_PULSE_OUTPUT.iter().enumerate() {
+            if i % 256 == 0 {
+                let pw = 0x200 + 0x100 * (i / 256);
+                sid.write(0x11, (pw >> 8) as u8); // PWHI3
+                sid.write(0x10, pw as u8); // PWLO3
+            }
+            clock(&mut sid);
+            assert_eq!(sid.read(0x1b), osc3, "pulse cycle {}", i);
+        }
+    }
+}
+
+#[test]
+fn noise_combined_waveforms() {
+    for &chip_model in [ChipModel::Mos6581, ChipModel::Mos8580].iter() {
+        for &clock_delta in [false, true].iter() {
+            let mut sid = Sid::new(chip_model);
+            sid.write(0x0f, 0xff); // FREQHI3
+            sid.write(0x0e, 0xff); // FREQLO3
+            sid.write(0x12, 0x80); // CR3, noise
+            sid.clock_delta(10_000);
+            let noise = sid.read(0x1b);
+            assert_ne!(noise, 0);
+            // Combining waveforms only clears noise output bits.
+            sid.write(0x12, 0xa0); // CR3, noise + sawtooth
+            assert_eq!(sid.read(0x1b) & !noise, 0);
+            // A zero pulse output clears the shift register and locks up the
+            // noise generator.
+            sid.write(0x11, 0x0f); // PWHI3
+            sid.write(0x10, 0xff); // PWLO3
+            sid.write(0x12, 0xc0); // CR3, noise + pulse
+            if clock_delta {
+                sid.clock_delta(100_000);
+            } else {
+                for _ in 0..100_000 {
+                    sid.clock();
+                }
+            }
+            sid.write(0x12, 0x80); // CR3, noise
+            for _ in 0..100 {
+                sid.clock_delta(1000);
+                assert_eq!(sid.read(0x1b), 0);
+            }
+            let state = sid.read_state();
+            assert_eq!(state.chip.shift_register[2], 0);
+        }
+    }
+}
+
+#[test]
+fn floating_waveform_output() {
+    let models = [
+        (ChipModel::Mos6581, 54_000, 1_400),
+        (ChipModel::Mos8580, 800_000, 50_000),
+    ];
+    for &(chip_model, ttl, fade) in models.iter() {
+        let mut sid = Sid::new(chip_model);
+        sid.enable_floating_output(true);
+        sid.write(0x0f, 0x10); // FREQHI3
+        sid.write(0x12, 0x20); // CR3, sawtooth
+        sid.clock_delta(4095);
+        assert_eq!(sid.read(0x1b), 0xff);
+        // The last level floats until the charge starts to leak away, then
+        // each fade step clears the bits below a cleared bit.
+        sid.write(0x12, 0x00); // CR3
+        sid.clock_delta(ttl - 1);
+        assert_eq!(sid.read(0x1b), 0xff);
+        let mut level = 0xff;
+        for _ in 0..8 {
+            sid.clock();
+            level &= level >> 1;
+            assert_eq!(sid.read(0x1b), level);
+            sid.clock_delta(fade - 1);
+            assert_eq!(sid.read(0x1b), level);
+        }
+        assert_eq!(level, 0);
+        // Fade steps are applied across clock_delta.
+        sid.write(0x12, 0x20); // CR3, sawtooth
+        sid.write(0x12, 0x00); // CR3
+        let level = sid.read(0x1b);
+        sid.clock_delta(ttl + 2 * fade);
+        assert_eq!(sid.read(0x1b), level & level >> 1 & level >> 2 & level >> 3);
+        // Without fading the level drops at the end of the floating time.
+        sid.set_floating_output_fade(0);
+        sid.write(0x12, 0x20); // CR3, sawtooth
+        sid.write(0x12, 0x00); // CR3
+        sid.clock_delta(ttl - 1);
+        assert_ne!(sid.read(0x1b), 0);
+        sid.clock();
+        assert_eq!(sid.read(0x1b), 0);
+        // Without a floating output the level drops at once.
+        sid.write(0x12, 0x20); // CR3, sawtooth
+        sid.set_floating_output_ttl(0);
+        sid.write(0x12, 0x00); // CR3
+        assert_eq!(sid.read(0x1b), 0);
+        // Off by default, like reSID 0.16.
+        let mut sid = Sid::new(chip_model);
+        sid.write(0x0f, 0x10); // FREQHI3
+        sid.write(0x12, 0x20); // CR3, sawtooth
+        sid.clock_delta(4095);
+        sid.write(0x12, 0x00); // CR3
+        assert_eq!(sid.read(0x1b), 0);
+    }
+}
@@ -0,0 +1,171 @@
+use resid::{ChipModel, Error, Sid, State};
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn setup_state() -> State {
+    let mut sid = Sid::new(ChipModel::Mos8580);
+    sid.write(0x05, 0x09); // AD1
+    sid.write(0x06, 0xf0); // SR1
+    sid.write(0x18, 0x1f); // MODVOL
+    sid.write(0x17, 0xf1); // RESFILT
+    sid.write(0x01, 25); // FREQHI1
+    sid.write(0x00, 177); // FREQLO1
+    sid.write(0x04, 0x41); // CR1
+    let mut buffer = [0i16; 128];
+    let _ = sid.sample(2000, &mut buffer, 1);
+    assert!(sid.write_at(100, 0x04, 0x40));
+    sid.read_state()
+}
+
+#[test]
+fn encode_decode() {
+    let state = setup_state();
+    let mut buf = vec![0u8; state.encoded_len()];
+    assert_eq!(state.encode(&mut buf), Ok(buf.len()));
+    assert_eq!(State::decode(&buf), Ok(state));
+}
+
+#[test]
+fn encode_buffer_too_small() {
+    let state = setup_state();
+    let mut buf = vec![0u8; state.encoded_len() - 1];
+    assert_eq!(state.encode(&mut buf), Err(Error::BufferTooSmall));
+}
+
+#[test]
+fn decode_invalid() {
+    let state = setup_state();
+    let mut buf = vec![0u8; state.encoded_len()];
+    state.encode(&mut buf).unwrap();
+    assert_eq!(State::decode(&buf[..8]), Err(Error::Truncated));
+
+    let mut corrupted = buf.clone();
+    corrupted[0] = b'X';
+    assert_eq!(State::decode(&corrupted), Err(Error::InvalidMagic));
+
+    let mut corrupted = buf.clone();
+    corrupted[4] = 0xff;
+    assert_eq!(
+        State::decode(&corrupted),
+        Err(Error::UnsupportedVersion(0xff))
+    );
+
+    let mut corrupted = buf.clone();
+    corrupted[20] ^= 0x01;
+    assert_eq!(State::decode(&corrupted), Err(Error::ChecksumMismatch));
+}
+
+#[test]
+fn decode_skips_unknown_sections() {
+    let state = setup_state();
+    let mut buf = vec![0u8; state.encoded_len()];
+    state.encode(&mut buf).unwrap();
+    buf.truncate(buf.len() - 4);
+    buf.extend_from_slice(&0x7fffu16.to_le_bytes());
+    buf.extend_from_slice(&3u32.to_le_bytes());
+    buf.extend_from_slice(&[1, 2, 3]);
+    let checksum = crc32(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    assert_eq!(State::decode(&buf), Ok(state));
+}
+
+type Corruption = (fn(&mut State), &'static str);
+
+#[test]
+fn write_state_rejects_invalid_fields() {
+    let state = setup_state();
+    let mut sid = Sid::new(ChipModel::Mos8580);
+    let registers = sid.read_state().chip.sid_register;
+    let invalid: [Corruption; 6] = [
+        (|s| s.chip.envelope_state[1] = 3, "envelope_state"),
+        (|s| s.chip.accumulator[0] = 0x0100_0000, "accumulator"),
+        (|s| s.chip.shift_register[2] = 0x0080_0000, "shift_register"),
+        (|s| s.chip.rate_counter[0] = 0x8000, "rate_counter"),
+        (
+            |s| s.chip.rate_counter_period[0] = 10,
+            "rate_counter_period",
+        ),
+        (|s| s.sampler_offset = i32::MAX, "sampler_offset"),
+    ];
+    for (corrupt, field) in invalid.iter() {
+        let mut corrupted = state.clone();
+        corrupt(&mut corrupted);
+        assert_eq!(sid.write_state(&corrupted), Err(Error::InvalidState(field)));
+        assert_eq!(sid.read_state().chip.sid_register, registers);
+    }
+    assert_eq!(sid.write_state(&state), Ok(()));
+}
+
+#[test]
+fn decode_rejects_invalid_fields() {
+    let mut state = setup_state();
+    state.chip.exponential_counter_period[0] = 3;
+    let mut buf = vec![0u8; state.encoded_len()];
+    state.encode(&mut buf).unwrap();
+    assert_eq!(
+        State::decode(&buf),
+        Err(Error::InvalidState("exponential_counter_period"))
+    );
+}
+
+#[test]
+fn default_state() {
+    let state = State::default();
+    assert_eq!(state.validate(), Ok(()));
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    assert_eq!(sid.read_state(), state);
+    assert_eq!(sid.try_read_state(), Ok(state.clone()));
+    assert_eq!(sid.write_state(&state), Ok(()));
+}
+
+#[test]
+fn decode_missing_section() {
+    let state = setup_state();
+    let mut buf = vec![0u8; state.encoded_len()];
+    state.encode(&mut buf).unwrap();
+    // Drop the VOICES section.
+    let mut stripped = buf[..6].to_vec();
+    let mut pos = 6;
+    while pos < buf.len() - 4 {
+        let tag = u16::from_le_bytes([buf[pos], buf[pos + 1]]);
+        let len = u32::from_le_bytes([buf[pos + 2], buf[pos + 3], buf[pos + 4], buf[pos + 5]]);
+        let end = pos + 6 + len as usize;
+        if tag != 2 {
+            stripped.extend_from_slice(&buf[pos..end]);
+        }
+        pos = end;
+    }
+    let checksum = crc32(&stripped);
+    stripped.extend_from_slice(&checksum.to_le_bytes());
+    let decoded = State::decode(&stripped).unwrap();
+    assert_eq!(decoded.chip.accumulator, State::default().chip.accumulator);
+    assert_eq!(decoded.chip.sid_register, state.chip.sid_register);
+    let mut sid = Sid::new(ChipModel::Mos8580);
+    assert_eq!(sid.write_state(&decoded), Ok(()));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn pending_write_to_read_only_register() {
+    let mut sid = Sid::new(ChipModel::Mos6581);
+    assert!(sid.write_at(10, 0x19, 0x55)); // POTX
+    assert!(!sid.write_at(20, 0x20, 0x55));
+    let state = sid.read_state();
+    assert_eq!(state.chip.pending_writes, vec![(10, 0x19, 0x55)]);
+    let mut restored = Sid::new(ChipModel::Mos6581);
+    assert_eq!(restored.write_state(&state), Ok(()));
+    assert_eq!(restored.read_state(), state);
+}
@@ -0,0 +1,43 @@
+use resid::voice::Voice;
+use resid::ChipModel;
+
+fn output(voice: &mut Voice, wave: u16, env: u8) -> i32 {
+    // Sawtooth outputs the upper 12 bits of the accumulator.
+    voice.wave.set_control(0x20);
+    voice.wave.acc = (wave as u32) << 12;
+    voice.envelope.envelope_counter = env;
+    voice.output(None)
+}
+
+#[test]
+fn dac_model_6581() {
+    let mut voice = Voice::new(ChipModel::Mos6581);
+    voice.set_dac_model(true);
+    // The MSB weighs less than all lower bits together.
+    assert!(output(&mut voice, 0x800, 0xff) < output(&mut voice, 0x7ff, 0xff));
+    assert!(output(&mut voice, 0xfff, 0x80) < output(&mut voice, 0xfff, 0x7f));
+    // Full scale is kept.
+    let full_scale = output(&mut voice, 0xfff, 0xff);
+    voice.set_dac_model(false);
+    assert_eq!(output(&mut voice, 0xfff, 0xff), full_scale);
+}
+
+#[test]
+fn dac_model_8580() {
+    let mut voice = Voice::new(ChipModel::Mos8580);
+    for wave in (0..0x1000).step_by(7) {
+        for &env in [0x00, 0x01, 0x7f, 0x80, 0xff].iter() {
+            // The ladders are linear, reSID scales the full scale output
+            // of (2^bits - 1) / 2^bits by 2^bits - 1, which drops the
+            // inputs above half scale by one step.
+            voice.set_dac_model(false);
+            let linear = output(
+                &mut voice,
+                wave - (wave > 0x800) as u16,
+                env - (env > 0x80) as u8,
+            );
+            voice.set_dac_model(true);
+            assert_eq!(output(&mut voice, wave, env), linear);
+        }
+    }
+}
@@ -194,3 +194,94 @@ fn waveform_delta_8() {
         );
     }
 }
+
+#[test]
+fn pipeline_delays_clock() {
+    for &(waveform, delay) in [(4, 1), (8, 2)].iter() {
+        let mut wave = WaveformGenerator::new(ChipModel::Mos6581);
+        let mut delayed = WaveformGenerator::new(ChipModel::Mos6581);
+        delayed.set_pipeline_delays(true);
+        setup(&mut wave, waveform, 16000, 1000);
+        setup(&mut delayed, waveform, 16000, 1000);
+        let mut outputs = [0u16; 3000];
+        for i in 0..3000 {
+            wave.clock();
+            delayed.clock();
+            outputs[i] = wave.output(None);
+            // The delayed output trails by `delay` cycles.
+            if i >= delay {
+                assert_eq!(delayed.output(None), outputs[i - delay]);
+            }
+        }
+    }
+}
+
+#[test]
+fn pipeline_delays_clock_delta() {
+    for &waveform in [4, 8].iter() {
+        let mut wave = WaveformGenerator::new(ChipModel::Mos6581);
+        let mut wave_delta = WaveformGenerator::new(ChipModel::Mos6581);
+        wave.set_pipeline_delays(true);
+        wave_delta.set_pipeline_delays(true);
+        setup(&mut wave, waveform, 16000, 1000);
+        setup(&mut wave_delta, waveform, 16000, 1000);
+        for i in 0..1500 {
+            let delta = 1 + (i * 7) % 40;
+            for _ in 0..delta {
+                wave.clock();
+            }
+            wave_delta.clock_delta(delta);
+            assert_eq!(wave_delta.output(None), wave.output(None));
+            assert_eq!(wave_delta.get_shift(), wave.get_shift());
+            assert_eq!(wave_delta.shift_pipeline, wave.shift_pipeline);
+        }
+    }
+}
+
+#[test]
+fn test_bit_shift_register_reset() {
+    for &(chip_model, period) in [
+        (ChipModel::Mos6581, 35_000),
+        (ChipModel::Mos8580, 2_519_864),
+    ]
+    .iter()
+    {
+        let mut wave = WaveformGenerator::new(chip_model);
+        wave.set_shift_reset_delay(true);
+        setup(&mut wave, 8, 16000, 0);
+        wave.clock_delta(100_000);
+        let shift = wave.get_shift();
+        // Holding TEST keeps the shift register until the reset time elapsed.
+        wave.set_control(0x88);
+        for _ in 0..period - 1 {
+            wave.clock();
+        }
+        assert_eq!(wave.get_shift(), shift);
+        // Releasing TEST completes a shift with bit 0 set to the inverse of bit 17.
+        wave.set_control(0x80);
+        assert_eq!(
+            wave.get_shift(),
+            ((shift << 1) & 0x7f_ffff) | (!shift >> 17 & 0x01)
+        );
+        wave.set_control(0x88);
+        wave.clock_delta(period);
+        assert_eq!(wave.get_shift(), 0x7f_ffff);
+        wave.set_control(0x80);
+        assert_eq!(wave.get_shift(), 0x7f_fffe);
+        assert_eq!(wave.output(None), 0x0ff0);
+    }
+}
+
+#[test]
+fn test_bit_shift_register_clear() {
+    // Without the reset delay TEST clears the shift register at once and
+    // sets it to 0x7ffff8 when released, like reSID 0.16.
+    let mut wave = WaveformGenerator::new(ChipModel::Mos6581);
+    setup(&mut wave, 8, 16000, 0);
+    wave.clock_delta(100_000);
+    wave.set_control(0x88);
+    assert_eq!(wave.get_shift(), 0);
+    wave.clock();
+    wave.set_control(0x80);
+    assert_eq!(wave.get_shift(), 0x7f_fff8);
+}